@@ -0,0 +1,168 @@
+use std::collections::BTreeSet;
+
+use log::info;
+use tokio::sync::watch;
+
+/// Why the node stopped taking part in the LAN cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PauseReason {
+    MeteredConnection,
+    UntrustedConnection,
+    OnBattery,
+    Sleeping,
+    ShuttingDown,
+}
+impl PauseReason {
+    fn pauses(self, scope: Scope) -> bool {
+        match self {
+            Self::MeteredConnection | Self::UntrustedConnection | Self::Sleeping => true,
+            Self::OnBattery | Self::ShuttingDown => scope == Scope::Serving,
+        }
+    }
+}
+
+/// What a pause stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Advertising and handing out files.
+    Serving,
+    /// Anything reaching other nodes: browsing, probing and `/cache` altogether.
+    Lan,
+}
+
+/// Whether the node advertises, browses, probes and serves its cache. Each
+/// scope stays paused as long as any reason pausing it holds.
+#[derive(Debug)]
+pub struct Activity {
+    reasons: watch::Sender<BTreeSet<PauseReason>>,
+    /// Whether the advertisement is published.
+    advertised: watch::Sender<bool>,
+    /// Counts the times the machine woke up.
+    wakeups: watch::Sender<u64>,
+}
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            reasons: watch::Sender::new(BTreeSet::new()),
+            advertised: watch::Sender::new(false),
+            wakeups: watch::Sender::new(0),
+        }
+    }
+}
+impl Activity {
+    pub fn set(&self, reason: PauseReason, paused: bool) {
+        let changed = self.reasons.send_if_modified(|reasons| {
+            if paused {
+                reasons.insert(reason)
+            } else {
+                reasons.remove(&reason)
+            }
+        });
+        match (changed, paused) {
+            (true, true) => info!("Pausing: {:?}", reason),
+            (true, false) => info!("No longer pausing: {:?}", reason),
+            _ => {}
+        }
+    }
+    pub fn holds(&self, reason: PauseReason) -> bool {
+        self.reasons.borrow().contains(&reason)
+    }
+    pub fn is_paused(&self, scope: Scope) -> bool {
+        is_paused(&self.reasons.borrow(), scope)
+    }
+    /// Returns once the scope is paused, or active again.
+    pub async fn wait_until_paused(&self, scope: Scope, paused: bool) {
+        let mut receiver = self.reasons.subscribe();
+        // The sender lives as long as self, so this cannot fail
+        let _ = receiver
+            .wait_for(|reasons| is_paused(reasons, scope) == paused)
+            .await;
+    }
+    /// Returns once the reason holds.
+    pub async fn wait_for(&self, reason: PauseReason) {
+        let mut receiver = self.reasons.subscribe();
+        let _ = receiver.wait_for(|reasons| reasons.contains(&reason)).await;
+    }
+    pub fn set_advertised(&self, advertised: bool) {
+        self.advertised.send_replace(advertised);
+    }
+    /// Returns once the advertisement is withdrawn, right away if there is
+    /// none.
+    pub async fn wait_until_withdrawn(&self) {
+        let mut receiver = self.advertised.subscribe();
+        let _ = receiver.wait_for(|advertised| !advertised).await;
+    }
+    /// Tells the tasks waiting in `woken` that the machine woke up, so that
+    /// they find out what changed on the LAN in the meantime.
+    pub fn wake(&self) {
+        self.wakeups.send_modify(|wakeups| *wakeups += 1);
+    }
+    /// Returns at the next wakeup.
+    pub async fn woken(&self) {
+        let mut receiver = self.wakeups.subscribe();
+        let _ = receiver.changed().await;
+    }
+}
+
+fn is_paused(reasons: &BTreeSet<PauseReason>, scope: Scope) -> bool {
+    reasons.iter().any(|reason| reason.pauses(scope))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, time::Duration};
+
+    use tokio::time::timeout;
+
+    use crate::activity::{Activity, PauseReason, Scope};
+
+    #[tokio::test]
+    async fn paused_while_any_reason_holds() {
+        let activity = Activity::default();
+        assert!(!activity.is_paused(Scope::Lan));
+        activity.set(PauseReason::MeteredConnection, true);
+        activity.set(PauseReason::UntrustedConnection, true);
+        activity.set(PauseReason::MeteredConnection, false);
+        assert!(activity.is_paused(Scope::Lan));
+        activity.wait_until_paused(Scope::Lan, true).await;
+        let resumed = activity.wait_until_paused(Scope::Lan, false);
+        assert!(
+            timeout(Duration::from_millis(10), resumed).await.is_err(),
+            "still paused"
+        );
+        activity.set(PauseReason::UntrustedConnection, false);
+        assert!(!activity.is_paused(Scope::Lan));
+        activity.wait_until_paused(Scope::Lan, false).await;
+    }
+    #[tokio::test]
+    async fn waits_for_withdrawal_and_wakeup() {
+        let activity = Activity::default();
+        activity.wait_until_withdrawn().await;
+        activity.set_advertised(true);
+        let withdrawn = activity.wait_until_withdrawn();
+        assert!(
+            timeout(Duration::from_millis(10), withdrawn).await.is_err(),
+            "still advertised"
+        );
+        activity.set_advertised(false);
+        activity.wait_until_withdrawn().await;
+
+        let mut woken = pin!(activity.woken());
+        assert!(
+            timeout(Duration::from_millis(10), &mut woken)
+                .await
+                .is_err()
+        );
+        activity.wake();
+        woken.await;
+    }
+    #[test]
+    fn battery_only_pauses_serving() {
+        let activity = Activity::default();
+        activity.set(PauseReason::OnBattery, true);
+        assert!(activity.is_paused(Scope::Serving));
+        assert!(!activity.is_paused(Scope::Lan));
+        activity.set(PauseReason::MeteredConnection, true);
+        assert!(activity.is_paused(Scope::Lan));
+    }
+}
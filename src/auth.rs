@@ -0,0 +1,600 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    HttpResponse,
+    body::{BodySize, BoxBody, MessageBody, to_bytes},
+    dev::{ServiceRequest, ServiceResponse},
+    error::{ErrorForbidden, ErrorInternalServerError},
+    http::{
+        Method, StatusCode,
+        header::{ContentType, HeaderMap, HeaderName, HeaderValue},
+    },
+    middleware::Next,
+    web::{self, Bytes},
+};
+use anyhow::{Context, Result, ensure};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::{AuthConfig, Trust};
+
+/// Key of the responding node, sent with signed responses.
+pub const KEY_HEADER: &str = "X-Cacheman-Key";
+/// Signature over the request signature and the response status.
+pub const SIGNATURE_HEADER: &str = "X-Cacheman-Signature";
+const KEY_PARAM: &str = "cacheman_key";
+const EXPIRES_PARAM: &str = "cacheman_expires";
+const SIGNATURE_PARAM: &str = "cacheman_sig";
+/// How long a signed URL stays valid. Redirects hand signed URLs to pacman,
+/// so this covers the time until it starts the download.
+const SIGNATURE_LIFETIME: Duration = Duration::from_secs(300);
+/// Tolerated clock difference between nodes.
+const CLOCK_SKEW: Duration = Duration::from_secs(60);
+/// Largest response body the middleware holds in memory to sign.
+const MAX_SIGNED_BODY_SIZE: u64 = 1 << 20;
+
+pub fn parse_public_key(key: &str) -> Result<VerifyingKey> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .context(format!("Invalid public key: {}", key))?;
+    VerifyingKey::from_bytes(&bytes).context(format!("Invalid public key: {}", key))
+}
+fn encode_public_key(key: &VerifyingKey) -> String {
+    URL_SAFE_NO_PAD.encode(key.as_bytes())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+fn body_digest(body: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(body))
+}
+fn request_message(method: &str, path: &str, expires: u64, body: &[u8]) -> String {
+    format!(
+        "cacheman-request\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        expires,
+        body_digest(body)
+    )
+}
+fn response_message(request_signature: &str, status: u16, body: &[u8]) -> String {
+    format!(
+        "cacheman-response\n{}\n{}\n{}",
+        request_signature,
+        status,
+        body_digest(body)
+    )
+}
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = rest.find('/').map_or("/", |i| &rest[i..]);
+    path.split_once('?').map_or(path, |(path, _)| path)
+}
+
+/// The Ed25519 identity of this node and the keys it trusts.
+///
+/// Requests between peers carry a signature of their method, path and body in
+/// their query, so a signed URL also works for pacman after a redirect.
+/// Responses to signed requests are signed in turn with their body, binding
+/// them to the request they answer.
+#[derive(Debug)]
+pub struct Auth {
+    /// `None` when authentication is disabled.
+    signing_key: Option<SigningKey>,
+    trust: Trust,
+    trusted_keys: HashSet<VerifyingKey>,
+    known_hosts_file: PathBuf,
+    known_hosts: Mutex<BTreeMap<String, VerifyingKey>>,
+}
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(Self::disabled());
+        }
+        let signing_key = load_or_generate_key(&config.key_file)?;
+        info!(
+            "Host key: {}",
+            encode_public_key(&signing_key.verifying_key())
+        );
+        let trusted_keys = config
+            .trusted_keys
+            .iter()
+            .map(|key| parse_public_key(key))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            signing_key: Some(signing_key),
+            trust: config.trust,
+            trusted_keys,
+            known_hosts: Mutex::new(load_known_hosts(&config.known_hosts_file)?),
+            known_hosts_file: config.known_hosts_file.clone(),
+        })
+    }
+    pub fn disabled() -> Self {
+        Self {
+            signing_key: None,
+            trust: Trust::Tofu,
+            trusted_keys: HashSet::new(),
+            known_hosts_file: PathBuf::new(),
+            known_hosts: Mutex::new(BTreeMap::new()),
+        }
+    }
+    /// Public key to advertise, if authentication is enabled.
+    pub fn public_key(&self) -> Option<String> {
+        let signing_key = self.signing_key.as_ref()?;
+        Some(encode_public_key(&signing_key.verifying_key()))
+    }
+    /// Whether to use the host, pinning its key if it is seen for the first
+    /// time. Without authentication every host is accepted.
+    pub fn check_host(&self, host: &str, key: Option<&str>) -> bool {
+        if self.signing_key.is_none() {
+            return true;
+        }
+        let Some(key) = key.and_then(|key| parse_public_key(key).ok()) else {
+            return false;
+        };
+        let mut known_hosts = self.known_hosts.lock().unwrap();
+        match known_hosts.get(host) {
+            Some(pinned) if *pinned == key => return true,
+            Some(_) => {
+                warn!("The host key of {} changed, ignoring the host", host);
+                return false;
+            }
+            None => {}
+        }
+        if self.trust == Trust::Explicit && !self.trusted_keys.contains(&key) {
+            return false;
+        }
+        known_hosts.insert(host.to_string(), key);
+        drop(known_hosts);
+        info!("Pinned the host key of {}", host);
+        if let Err(e) = self.save_known_host(host, &key) {
+            warn!("Failed to save the host key of {}: {:#}", host, e);
+        }
+        true
+    }
+    fn save_known_host(&self, host: &str, key: &VerifyingKey) -> Result<()> {
+        if let Some(parent) = self.known_hosts_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.known_hosts_file)?;
+        writeln!(file, "{} {}", host, encode_public_key(key))?;
+        Ok(())
+    }
+    fn is_trusted(&self, key: &VerifyingKey) -> bool {
+        self.trusted_keys.contains(key)
+            || self
+                .known_hosts
+                .lock()
+                .unwrap()
+                .values()
+                .any(|known| known == key)
+    }
+    /// Appends a signature for downloading the URL, unless authentication is
+    /// disabled.
+    pub fn sign_url(&self, url: String) -> String {
+        self.sign_request("GET", url, &[])
+    }
+    /// Appends a signature of the method, the path of the URL and the body,
+    /// unless authentication is disabled.
+    pub fn sign_request(&self, method: &str, url: String, body: &[u8]) -> String {
+        let Some(signing_key) = &self.signing_key else {
+            return url;
+        };
+        let expires = unix_time() + SIGNATURE_LIFETIME.as_secs();
+        let message = request_message(method, url_path(&url), expires, body);
+        let signature = signing_key.sign(message.as_bytes());
+        format!(
+            "{}?{}={}&{}={}&{}={}",
+            url,
+            KEY_PARAM,
+            encode_public_key(&signing_key.verifying_key()),
+            EXPIRES_PARAM,
+            expires,
+            SIGNATURE_PARAM,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+    /// The key that validly signed the request, regardless of whether it is
+    /// trusted.
+    fn request_key(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        body: &[u8],
+    ) -> Option<VerifyingKey> {
+        let key = parse_public_key(query_param(query, KEY_PARAM)?).ok()?;
+        let expires = query_param(query, EXPIRES_PARAM)?.parse::<u64>().ok()?;
+        let now = unix_time();
+        if expires < now || expires > now + (SIGNATURE_LIFETIME + CLOCK_SKEW).as_secs() {
+            return None;
+        }
+        let signature = decode_signature(query_param(query, SIGNATURE_PARAM)?)?;
+        // A URL signed for a download also serves to probe for the file
+        let method = if method == "HEAD" { "GET" } else { method };
+        key.verify(
+            request_message(method, path, expires, body).as_bytes(),
+            &signature,
+        )
+        .ok()?;
+        Some(key)
+    }
+    /// Whether the request is signed by a trusted key.
+    pub fn authorize(&self, method: &str, path: &str, query: &str, body: &[u8]) -> bool {
+        self.signing_key.is_none()
+            || self
+                .request_key(method, path, query, body)
+                .is_some_and(|key| self.is_trusted(&key))
+    }
+    /// Like `authorize`, but also accepts an unknown key the request
+    /// introduces for one of the hosts, which lets gossip bootstrap trust on
+    /// first use.
+    pub fn authorize_introduced<'a>(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        body: &[u8],
+        introduced: impl IntoIterator<Item = (&'a str, Option<&'a str>)>,
+    ) -> bool {
+        if self.signing_key.is_none() {
+            return true;
+        }
+        let Some(key) = self.request_key(method, path, query, body) else {
+            return false;
+        };
+        if self.is_trusted(&key) {
+            return true;
+        }
+        let encoded = encode_public_key(&key);
+        introduced
+            .into_iter()
+            .any(|(host, host_key)| host_key == Some(&encoded) && self.check_host(host, host_key))
+    }
+    /// Headers signing the response to a request with a signed query.
+    pub fn sign_response(
+        &self,
+        query: &str,
+        status: u16,
+        body: &[u8],
+    ) -> Option<[(&'static str, String); 2]> {
+        let signing_key = self.signing_key.as_ref()?;
+        let request_signature = query_param(query, SIGNATURE_PARAM)?;
+        let message = response_message(request_signature, status, body);
+        let signature = signing_key.sign(message.as_bytes());
+        Some([
+            (KEY_HEADER, encode_public_key(&signing_key.verifying_key())),
+            (
+                SIGNATURE_HEADER,
+                URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            ),
+        ])
+    }
+    /// A JSON response, signed for the request with the query.
+    pub fn signed_json(
+        &self,
+        query: &str,
+        value: &impl Serialize,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let body = serde_json::to_vec(value).map_err(ErrorInternalServerError)?;
+        let mut response = HttpResponse::Ok();
+        response.content_type(ContentType::json());
+        let signature = self.sign_response(query, StatusCode::OK.as_u16(), &body);
+        let mut response = response.body(body);
+        add_signature_headers(response.headers_mut(), signature);
+        Ok(response)
+    }
+    /// Reads the body of the response to the signed URL, making sure it comes
+    /// from the trusted key of the host. Without authentication every response
+    /// is accepted.
+    pub async fn read_verified(
+        &self,
+        host: &str,
+        url: &str,
+        response: reqwest::Response,
+    ) -> Result<Bytes> {
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+        ensure!(
+            self.verify_response(host, url, status, &headers, &body),
+            "{} is not trusted",
+            host
+        );
+        Ok(body)
+    }
+    fn verify_response(
+        &self,
+        host: &str,
+        url: &str,
+        status: u16,
+        headers: &reqwest::header::HeaderMap,
+        body: &[u8],
+    ) -> bool {
+        if self.signing_key.is_none() {
+            return true;
+        }
+        let result = (|| {
+            let key = headers.get(KEY_HEADER)?.to_str().ok()?;
+            let signature = decode_signature(headers.get(SIGNATURE_HEADER)?.to_str().ok()?)?;
+            let (_, query) = url.split_once('?')?;
+            let request_signature = query_param(query, SIGNATURE_PARAM)?;
+            if !self.check_host(host, Some(key)) {
+                return None;
+            }
+            let message = response_message(request_signature, status, body);
+            parse_public_key(key)
+                .ok()?
+                .verify(message.as_bytes(), &signature)
+                .ok()
+        })();
+        result.is_some()
+    }
+}
+
+fn decode_signature(signature: &str) -> Option<Signature> {
+    let bytes = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    Some(Signature::from_bytes(&bytes.try_into().ok()?))
+}
+
+fn load_or_generate_key(path: &Path) -> Result<SigningKey> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let bytes = <[u8; 32]>::try_from(bytes)
+                .ok()
+                .context(format!("Invalid host key in {}", path.display()))?;
+            return Ok(SigningKey::from_bytes(&bytes));
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+    }
+    let signing_key = SigningKey::from_bytes(&rand::random());
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(signing_key.as_bytes()))
+        .context(format!("Failed to write {}", path.display()))?;
+    info!("Generated a host key in {}", path.display());
+    Ok(signing_key)
+}
+
+fn load_known_hosts(path: &Path) -> Result<BTreeMap<String, VerifyingKey>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+    };
+    let mut known_hosts = BTreeMap::new();
+    for line in content.lines() {
+        let Some((host, key)) = line.split_once(' ') else {
+            continue;
+        };
+        known_hosts.insert(host.to_string(), parse_public_key(key.trim())?);
+    }
+    Ok(known_hosts)
+}
+
+/// Rejects requests not signed by a trusted peer and signs the responses.
+/// The endpoints behind it take no body. Responses too large to hold in memory
+/// are left unsigned; pacman checks the packages themselves.
+pub async fn authenticate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let auth = request
+        .app_data::<web::Data<Auth>>()
+        .cloned()
+        .expect("Auth is registered as app data");
+    let query = request.query_string().to_string();
+    let method = request.method().clone();
+    if !auth.authorize(method.as_str(), request.path(), &query, &[]) {
+        return Err(ErrorForbidden("Not signed by a trusted peer"));
+    }
+    let response = next.call(request).await?;
+    let status = response.status().as_u16();
+    // The body of a response to HEAD is never sent
+    if method == Method::HEAD {
+        let mut response = response.map_into_boxed_body();
+        add_signature_headers(
+            response.headers_mut(),
+            auth.sign_response(&query, status, &[]),
+        );
+        return Ok(response);
+    }
+    match response.response().body().size() {
+        BodySize::None => {}
+        BodySize::Sized(size) if size <= MAX_SIGNED_BODY_SIZE => {}
+        _ => return Ok(response.map_into_boxed_body()),
+    }
+    let (request, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+    let body = to_bytes(body)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.into()))?;
+    let signature = auth.sign_response(&query, status, &body);
+    let mut response = response.set_body(body).map_into_boxed_body();
+    add_signature_headers(response.headers_mut(), signature);
+    Ok(ServiceResponse::new(request, response))
+}
+
+pub fn add_signature_headers(
+    headers: &mut HeaderMap,
+    signature: Option<[(&'static str, String); 2]>,
+) {
+    for (name, value) in signature.into_iter().flatten() {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use tempfile::{TempDir, tempdir};
+
+    use crate::{
+        auth::{Auth, url_path},
+        config::{AuthConfig, Trust},
+    };
+
+    fn auth(dir: &TempDir, name: &str, trust: Trust, trusted_keys: Vec<String>) -> Result<Auth> {
+        Auth::new(&AuthConfig {
+            enabled: true,
+            key_file: dir.path().join(format!("{}.key", name)),
+            known_hosts_file: dir.path().join(format!("{}.known_hosts", name)),
+            trust,
+            trusted_keys,
+        })
+    }
+    fn query(url: &str) -> &str {
+        url.split_once('?').unwrap().1
+    }
+
+    #[test]
+    fn key_is_kept() -> Result<()> {
+        let dir = tempdir()?;
+        let first = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        let second = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        assert!(first.public_key().is_some());
+        assert_eq!(first.public_key(), second.public_key());
+        Ok(())
+    }
+    #[test]
+    fn signed_url() -> Result<()> {
+        let dir = tempdir()?;
+        let a = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        let b = auth(&dir, "b", Trust::Tofu, Vec::new())?;
+        let url = a.sign_url("http://b:1052/cache/foo.pkg.tar.zst".to_string());
+        assert_eq!(url_path(&url), "/cache/foo.pkg.tar.zst");
+        // Valid, but from an unknown key
+        assert!(!b.authorize("GET", "/cache/foo.pkg.tar.zst", query(&url), &[]));
+        assert!(b.check_host("a", a.public_key().as_deref()));
+        assert!(b.authorize("GET", "/cache/foo.pkg.tar.zst", query(&url), &[]));
+        assert!(b.authorize("HEAD", "/cache/foo.pkg.tar.zst", query(&url), &[]));
+        assert!(!b.authorize("GET", "/cache/bar.pkg.tar.zst", query(&url), &[]));
+        assert!(!b.authorize("GET", "/cache/foo.pkg.tar.zst", "", &[]));
+        Ok(())
+    }
+    #[test]
+    fn tampered_request_is_rejected() -> Result<()> {
+        let dir = tempdir()?;
+        let a = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        let b = auth(&dir, "b", Trust::Tofu, Vec::new())?;
+        assert!(b.check_host("a", a.public_key().as_deref()));
+        let url = a.sign_request("POST", "http://b:1052/lease".to_string(), b"{}");
+        assert!(b.authorize("POST", "/lease", query(&url), b"{}"));
+        assert!(!b.authorize("POST", "/lease", query(&url), b"{\"holder\":\"c\"}"));
+        assert!(!b.authorize("PUT", "/lease", query(&url), b"{}"));
+        assert!(!b.authorize("GET", "/lease", query(&url), &[]));
+        Ok(())
+    }
+    #[test]
+    fn tampered_response_is_rejected() -> Result<()> {
+        let dir = tempdir()?;
+        let a = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        let b = auth(&dir, "b", Trust::Tofu, Vec::new())?;
+        let url = a.sign_request("POST", "http://b:1052/gossip".to_string(), b"{}");
+        let mut headers = HeaderMap::new();
+        for (name, value) in b.sign_response(query(&url), 200, b"ok").unwrap() {
+            headers.insert(name, HeaderValue::from_str(&value)?);
+        }
+        assert!(a.verify_response("b", &url, 200, &headers, b"ok"));
+        assert!(!a.verify_response("b", &url, 200, &headers, b"ko"));
+        assert!(!a.verify_response("b", &url, 500, &headers, b"ok"));
+        Ok(())
+    }
+    #[test]
+    fn tofu_pins_first_key() -> Result<()> {
+        let dir = tempdir()?;
+        let a = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        let b = auth(&dir, "b", Trust::Tofu, Vec::new())?;
+        let c = auth(&dir, "c", Trust::Tofu, Vec::new())?;
+        assert!(a.check_host("b", b.public_key().as_deref()));
+        assert!(!a.check_host("b", c.public_key().as_deref()));
+        assert!(!a.check_host("c", None));
+        // Pins survive a restart
+        let a = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        assert!(!a.check_host("b", c.public_key().as_deref()));
+        Ok(())
+    }
+    #[test]
+    fn explicit_trust() -> Result<()> {
+        let dir = tempdir()?;
+        let b = auth(&dir, "b", Trust::Tofu, Vec::new())?;
+        let c = auth(&dir, "c", Trust::Tofu, Vec::new())?;
+        let a = auth(
+            &dir,
+            "a",
+            Trust::Explicit,
+            b.public_key().into_iter().collect(),
+        )?;
+        assert!(a.check_host("b", b.public_key().as_deref()));
+        assert!(!a.check_host("c", c.public_key().as_deref()));
+        Ok(())
+    }
+    #[test]
+    fn introduced_key() -> Result<()> {
+        let dir = tempdir()?;
+        let a = auth(&dir, "a", Trust::Tofu, Vec::new())?;
+        let b = auth(&dir, "b", Trust::Tofu, Vec::new())?;
+        let url = b.sign_url("http://a:1052/gossip".to_string());
+        let key = b.public_key();
+        assert!(!a.authorize_introduced("GET", "/gossip", query(&url), &[], [("b", None)]));
+        assert!(a.authorize_introduced(
+            "GET",
+            "/gossip",
+            query(&url),
+            &[],
+            [("b", key.as_deref())]
+        ));
+        assert!(a.authorize("GET", "/gossip", query(&url), &[]));
+        Ok(())
+    }
+    #[test]
+    fn disabled() {
+        let auth = Auth::disabled();
+        assert_eq!(auth.public_key(), None);
+        assert!(auth.check_host("a", None));
+        assert!(auth.authorize("GET", "/cache/foo", "", &[]));
+        assert_eq!(
+            auth.sign_url("http://a/cache/foo".to_string()),
+            "http://a/cache/foo"
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+};
+
+use actix_files::{Files, FilesService, NamedFile};
+use actix_web::{
+    HttpRequest, HttpResponse,
+    body::{BodySize, MessageBody, SizedStream},
+    dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse},
+    error::ErrorInternalServerError,
+    http::{Method, header::CONTENT_RANGE},
+    web,
+};
+use tokio::fs::metadata;
+
+use crate::{cache_policy::CachePolicy, config::IoPriority, pacman::Pacman, upload::read_chunks};
+
+fn files(cache_dir: &Path, cache_policy: &web::Data<CachePolicy>) -> Files {
+    let mut files = Files::new("/", cache_dir)
+        .use_last_modified(true)
+        .path_filter({
+            let cache_policy = cache_policy.clone();
+            move |path, _| !cache_policy.is_path_excluded(path)
+        });
+    if cache_policy.lists_files() {
+        let cache_policy = cache_policy.clone();
+        files = files
+            .show_files_listing()
+            .files_listing_renderer(move |directory, request| {
+                cache_policy.render_listing(directory, request)
+            });
+    }
+    files
+}
+
+/// Looks through the cache directories in order, like pacman does.
+fn chained_files(cache_dirs: &[PathBuf], cache_policy: &web::Data<CachePolicy>) -> Option<Files> {
+    cache_dirs.iter().rev().fold(None, |fallback, cache_dir| {
+        let files = files(cache_dir, cache_policy);
+        Some(match fallback {
+            Some(fallback) => files.default_handler(fallback),
+            None => files,
+        })
+    })
+}
+
+/// Where a response for the file starts in it: after the first byte of its
+/// `Content-Range`, or at the beginning.
+fn range_start(response: &HttpResponse) -> Option<u64> {
+    let Some(content_range) = response.headers().get(CONTENT_RANGE) else {
+        return Some(0);
+    };
+    let range = content_range.to_str().ok()?.strip_prefix("bytes ")?;
+    range.split_once('-')?.0.parse().ok()
+}
+
+/// Answers like actix-files, but reads the file at the I/O priority.
+fn serve_at_priority(
+    file: NamedFile,
+    request: &HttpRequest,
+    priority: IoPriority,
+) -> Result<HttpResponse, actix_web::Error> {
+    let reader = file.file().try_clone()?;
+    let response = file.use_last_modified(true).into_response(request);
+    let BodySize::Sized(length) = response.body().size() else {
+        return Ok(response);
+    };
+    let Some(offset) = range_start(&response).filter(|_| response.status().is_success()) else {
+        return Ok(response);
+    };
+    let chunks = read_chunks(reader, offset, length, priority);
+    Ok(response
+        .set_body(SizedStream::new(length, chunks))
+        .map_into_boxed_body())
+}
+
+/// Serves `/cache` from the cache directories of the current pacman state.
+/// Each worker builds its files service again once the directories change.
+pub struct CacheFiles {
+    pacman: web::Data<Pacman>,
+    cache_policy: web::Data<CachePolicy>,
+    io_priority: IoPriority,
+    current: RefCell<Option<(Vec<PathBuf>, FilesService)>>,
+}
+impl CacheFiles {
+    pub fn new(
+        pacman: web::Data<Pacman>,
+        cache_policy: web::Data<CachePolicy>,
+        io_priority: IoPriority,
+    ) -> Self {
+        Self {
+            pacman,
+            cache_policy,
+            io_priority,
+            current: RefCell::new(None),
+        }
+    }
+    /// The file downloaded by the request, if it names one plainly: anything
+    /// else is left to actix-files.
+    async fn requested_file(&self, request: &ServiceRequest) -> Option<PathBuf> {
+        let name = request.match_info().unprocessed().strip_prefix('/')?;
+        if request.method() != Method::GET
+            || name.is_empty()
+            || name.starts_with('.')
+            || name.contains(['/', '%'])
+            || self.cache_policy.is_path_excluded(Path::new(name))
+        {
+            return None;
+        }
+        for cache_dir in &self.pacman.current().cache_dirs {
+            let path = cache_dir.join(name);
+            if metadata(&path)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                return Some(path);
+            }
+        }
+        None
+    }
+    async fn service(&self) -> Result<FilesService, actix_web::Error> {
+        let cache_dirs = self.pacman.current().cache_dirs.clone();
+        if let Some((dirs, service)) = &*self.current.borrow()
+            && *dirs == cache_dirs
+        {
+            return Ok(service.clone());
+        }
+        let service = chained_files(&cache_dirs, &self.cache_policy)
+            .ok_or_else(|| ErrorInternalServerError("No cache directory"))?
+            .new_service(())
+            .await
+            .map_err(|()| ErrorInternalServerError("Failed to serve the cache directories"))?;
+        *self.current.borrow_mut() = Some((cache_dirs, service.clone()));
+        Ok(service)
+    }
+    pub async fn call(&self, request: ServiceRequest) -> Result<ServiceResponse, actix_web::Error> {
+        // actix-files reads on blocking threads shared with the rest of the
+        // server, so files are only read at a lower priority when served here
+        if self.io_priority != IoPriority::Normal
+            && let Some(path) = self.requested_file(&request).await
+            && let Ok(file) = NamedFile::open_async(&path).await
+        {
+            let (request, _) = request.into_parts();
+            let response = serve_at_priority(file, &request, self.io_priority)?;
+            return Ok(ServiceResponse::new(request, response));
+        }
+        self.service().await?.call(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, rc::Rc};
+
+    use actix_web::{
+        App,
+        dev::fn_service,
+        http::{StatusCode, header::RANGE},
+        test::{TestRequest, call_service, init_service, read_body},
+        web::{Data, scope},
+    };
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use crate::{
+        cache_files::CacheFiles,
+        cache_policy::CachePolicy,
+        config::{CacheConfig, IoPriority, Listing},
+        pacman::{Pacman, PacmanState},
+    };
+
+    #[actix_web::test]
+    async fn follows_cache_dirs() -> Result<()> {
+        let (old, new, extra) = (tempdir()?, tempdir()?, tempdir()?);
+        fs::write(old.path().join("old.pkg.tar.zst"), "old")?;
+        fs::write(new.path().join("new.pkg.tar.zst"), "new")?;
+        fs::write(extra.path().join("extra.pkg.tar.zst"), "extra")?;
+        let pacman = Data::new(Pacman::new(PacmanState {
+            cache_dirs: vec![old.path().to_path_buf()],
+            ..Default::default()
+        }));
+        let cache_policy = Data::new(CachePolicy::new(&CacheConfig::default()));
+        let cache_files = Rc::new(CacheFiles::new(
+            pacman.clone(),
+            cache_policy,
+            IoPriority::Normal,
+        ));
+        let app = init_service(
+            App::new().service(scope("/cache").default_service(fn_service(move |request| {
+                let cache_files = cache_files.clone();
+                async move { cache_files.call(request).await }
+            }))),
+        )
+        .await;
+        let status = |path: &str| {
+            let request = TestRequest::get().uri(path).to_request();
+            let response = call_service(&app, request);
+            async move { response.await.status() }
+        };
+        assert_eq!(status("/cache/old.pkg.tar.zst").await, StatusCode::OK);
+        assert_eq!(
+            status("/cache/new.pkg.tar.zst").await,
+            StatusCode::NOT_FOUND
+        );
+
+        pacman.replace(PacmanState {
+            cache_dirs: vec![new.path().to_path_buf(), extra.path().to_path_buf()],
+            ..Default::default()
+        });
+        assert_eq!(
+            status("/cache/old.pkg.tar.zst").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status("/cache/new.pkg.tar.zst").await, StatusCode::OK);
+        assert_eq!(status("/cache/extra.pkg.tar.zst").await, StatusCode::OK);
+        Ok(())
+    }
+    #[actix_web::test]
+    async fn serves_at_low_priority() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.pkg.tar.zst"), "0123456789")?;
+        let pacman = Data::new(Pacman::new(PacmanState {
+            cache_dirs: vec![dir.path().to_path_buf()],
+            ..Default::default()
+        }));
+        let cache_policy = Data::new(CachePolicy::new(&CacheConfig::default()));
+        let cache_files = Rc::new(CacheFiles::new(pacman, cache_policy, IoPriority::Idle));
+        let app = init_service(
+            App::new().service(scope("/cache").default_service(fn_service(move |request| {
+                let cache_files = cache_files.clone();
+                async move { cache_files.call(request).await }
+            }))),
+        )
+        .await;
+        let request = TestRequest::get().uri("/cache/a.pkg.tar.zst").to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_body(response).await, "0123456789");
+        let request = TestRequest::get()
+            .uri("/cache/a.pkg.tar.zst")
+            .insert_header((RANGE, "bytes=2-4"))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(read_body(response).await, "234");
+        let request = TestRequest::get().uri("/cache/b.pkg.tar.zst").to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+    #[actix_web::test]
+    async fn lists_root() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.pkg.tar.zst"), "a")?;
+        for (listing, lan, loopback) in [
+            (Listing::On, StatusCode::OK, StatusCode::OK),
+            (Listing::Off, StatusCode::NOT_FOUND, StatusCode::NOT_FOUND),
+            (Listing::Restricted, StatusCode::FORBIDDEN, StatusCode::OK),
+        ] {
+            let pacman = Data::new(Pacman::new(PacmanState {
+                cache_dirs: vec![dir.path().to_path_buf()],
+                ..Default::default()
+            }));
+            let cache_policy = Data::new(CachePolicy::new(&CacheConfig {
+                listing,
+                ..Default::default()
+            }));
+            let cache_files = Rc::new(CacheFiles::new(pacman, cache_policy, IoPriority::Normal));
+            let app = init_service(App::new().service(scope("/cache").default_service(
+                fn_service(move |request| {
+                    let cache_files = cache_files.clone();
+                    async move { cache_files.call(request).await }
+                }),
+            )))
+            .await;
+            for (uri, peer, expected) in [
+                ("/cache/", "192.168.1.2:40000", lan),
+                ("/cache", "192.168.1.2:40000", lan),
+                ("/cache/", "127.0.0.1:40000", loopback),
+            ] {
+                let request = TestRequest::get()
+                    .uri(uri)
+                    .peer_addr(peer.parse()?)
+                    .to_request();
+                let response = call_service(&app, request).await;
+                assert_eq!(response.status(), expected, "{listing:?} {uri} from {peer}");
+                if expected == StatusCode::OK {
+                    let body = read_body(response).await;
+                    assert!(String::from_utf8_lossy(&body).contains("a.pkg.tar.zst"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
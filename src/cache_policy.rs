@@ -0,0 +1,189 @@
+use std::{fmt::Write, io, net::IpAddr, path::Path};
+
+use actix_files::Directory;
+use actix_web::{
+    HttpRequest, HttpResponse,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web,
+};
+use glob::Pattern;
+
+use crate::{
+    config::{CacheConfig, Listing},
+    peer_registry::proximity::interface_networks,
+};
+
+/// Who may use `/cache` and what it shares. Endpoints revealing which peers
+/// exist or which files are cached follow the same client rules.
+#[derive(Debug)]
+pub struct CachePolicy {
+    config: CacheConfig,
+    exclude: Vec<Pattern>,
+}
+impl CachePolicy {
+    pub fn new(config: &CacheConfig) -> Self {
+        let exclude = config
+            .exclude
+            .iter()
+            // Validated when the configuration was loaded
+            .filter_map(|pattern| Pattern::new(pattern).ok())
+            .collect();
+        Self {
+            config: config.clone(),
+            exclude,
+        }
+    }
+    pub fn allows_client(&self, ip: IpAddr) -> bool {
+        self.config.allows_client(ip, interface_networks)
+    }
+    pub fn allows_request(&self, request: &HttpRequest) -> bool {
+        request
+            .peer_addr()
+            .is_some_and(|addr| self.allows_client(addr.ip()))
+    }
+    pub fn lists_files(&self) -> bool {
+        self.config.listing != Listing::Off
+    }
+    /// A signature is excluded along with its package.
+    pub fn is_excluded(&self, file_name: &str) -> bool {
+        let package = file_name.strip_suffix(".sig").unwrap_or(file_name);
+        self.exclude.iter().any(|pattern| pattern.matches(package))
+    }
+    /// The directory root has no file name and is never excluded.
+    pub fn is_path_excluded(&self, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.is_excluded(&name.to_string_lossy()))
+    }
+    /// Lists the shared files, leaving out the excluded ones and pacman's
+    /// partial downloads.
+    pub fn render_listing(
+        &self,
+        directory: &Directory,
+        request: &HttpRequest,
+    ) -> Result<ServiceResponse, io::Error> {
+        let allowed = request
+            .peer_addr()
+            .is_some_and(|addr| self.config.allows_listing(addr.ip()));
+        if !allowed {
+            return Ok(ServiceResponse::new(
+                request.clone(),
+                HttpResponse::Forbidden().finish(),
+            ));
+        }
+        let mut names = Vec::new();
+        for entry in directory.path.read_dir()? {
+            if !directory.is_visible(&entry) {
+                continue;
+            }
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.metadata()?.is_file() && !name.ends_with(".part") && !self.is_excluded(&name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        let title = escape_html(&format!("Index of {}", request.path()));
+        let mut body = String::new();
+        for name in names {
+            let name = escape_html(&name);
+            let _ = write!(body, "<li><a href=\"{}\">{}</a></li>", name, name);
+        }
+        let html = format!(
+            "<html><head><title>{}</title></head><body><h1>{}</h1><ul>{}</ul></body>\n</html>",
+            title, title, body
+        );
+        Ok(ServiceResponse::new(
+            request.clone(),
+            HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(html),
+        ))
+    }
+}
+
+/// Answers 403 to clients not allowed to use the endpoints meant for peers,
+/// like the endpoints checking it themselves.
+pub async fn check_client(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let cache_policy = request
+        .app_data::<web::Data<CachePolicy>>()
+        .cloned()
+        .expect("CachePolicy is registered as app data");
+    if !cache_policy.allows_request(request.request()) {
+        let response = HttpResponse::Forbidden().body("Not allowed");
+        return Ok(request.into_response(response));
+    }
+    Ok(next.call(request).await?.map_into_boxed_body())
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App, HttpResponse,
+        http::StatusCode,
+        middleware::from_fn,
+        test::{TestRequest, call_service, init_service},
+        web::{Data, get, resource},
+    };
+
+    use crate::{
+        cache_policy::{CachePolicy, check_client},
+        config::CacheConfig,
+    };
+
+    #[actix_web::test]
+    async fn forbids_other_clients() {
+        let policy = Data::new(CachePolicy::new(&CacheConfig {
+            allow_networks: vec!["192.168.1.0/24".parse().unwrap()],
+            ..Default::default()
+        }));
+        let app = init_service(
+            App::new().app_data(policy).service(
+                resource("/cache")
+                    .wrap(from_fn(check_client))
+                    .route(get().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let status = |peer: &str| {
+            let request = TestRequest::get()
+                .uri("/cache")
+                .peer_addr(peer.parse().unwrap())
+                .to_request();
+            let response = call_service(&app, request);
+            async move { response.await.status() }
+        };
+        assert_eq!(status("192.168.1.2:40000").await, StatusCode::OK);
+        assert_eq!(status("203.0.113.1:40000").await, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn exclude() {
+        let policy = CachePolicy::new(&CacheConfig {
+            exclude: vec!["*-proprietary-*".to_string(), "secret-*".to_string()],
+            ..Default::default()
+        });
+        assert!(policy.is_excluded("foo-proprietary-1.0-1-x86_64.pkg.tar.zst"));
+        assert!(policy.is_excluded("secret-tool-1.0-1-any.pkg.tar.zst.sig"));
+        assert!(!policy.is_excluded("linux-6.9-1-x86_64.pkg.tar.zst"));
+    }
+}
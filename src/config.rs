@@ -0,0 +1,1038 @@
+use std::{
+    io::ErrorKind,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, ensure};
+use chrono::NaiveTime;
+use glob::Pattern;
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::fs::read_to_string;
+
+use crate::auth::parse_public_key;
+
+const DEFAULT_CONFIG_FILE_PATH: &str = "/etc/cacheman.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub node: NodeConfig,
+    pub discovery: DiscoveryConfig,
+    pub gossip: GossipConfig,
+    pub proxy: ProxyConfig,
+    pub parent: ParentConfig,
+    pub cache: CacheConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub upload: UploadConfig,
+    pub network: NetworkConfig,
+    pub power: PowerConfig,
+}
+impl Config {
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.node.serves_cache() || self.node.proxies(),
+            "The node neither serves its cache nor proxies"
+        );
+        for key in self.auth.trusted_keys.iter() {
+            parse_public_key(key)?;
+        }
+        for pattern in self.cache.exclude.iter() {
+            Pattern::new(pattern).context(format!("Invalid exclude pattern: {}", pattern))?;
+        }
+        // Gossip settings only matter when it runs
+        ensure!(
+            !self.gossip.enabled || self.gossip.interval_secs > 0,
+            "The gossip interval must be positive"
+        );
+        let limits = self
+            .upload
+            .schedule
+            .iter()
+            .map(|window| &window.limits)
+            .chain([&self.upload.limits]);
+        for limits in limits {
+            ensure!(
+                limits.max_transfers != Some(0),
+                "max_transfers must be positive, leave it unset for no limit"
+            );
+        }
+        if let Some(cluster) = &self.discovery.cluster {
+            // The cluster is published as the DNS-SD subtype label `_<cluster>`
+            ensure!(
+                !cluster.is_empty()
+                    && cluster.len() < 63
+                    && cluster
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-'),
+                "Invalid cluster name: {}",
+                cluster
+            );
+        }
+        Ok(())
+    }
+    /// Whether upstream mirrors are needed: to send pacman to them, or to pull
+    /// files from them as a parent.
+    pub fn uses_upstream(&self) -> bool {
+        self.proxy.mode == ProxyMode::Full || self.parent.enabled
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Shares its cache and uses the caches of others.
+    #[default]
+    Full,
+    /// Only uses the caches of others, e.g. a live ISO or a CI container.
+    Client,
+    /// Only shares its cache, e.g. a headless file server.
+    Server,
+}
+
+/// What the node takes part in. Each switch defaults to what the role implies.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub role: Role,
+    pub advertise: Option<bool>,
+    pub serve_cache: Option<bool>,
+    pub proxy: Option<bool>,
+    pub browse: Option<bool>,
+}
+impl NodeConfig {
+    pub fn advertises(&self) -> bool {
+        self.advertise.unwrap_or(self.serves_cache())
+    }
+    pub fn serves_cache(&self) -> bool {
+        self.serve_cache.unwrap_or(self.role != Role::Client)
+    }
+    pub fn proxies(&self) -> bool {
+        self.proxy.unwrap_or(self.role != Role::Server)
+    }
+    pub fn browses(&self) -> bool {
+        self.browse.unwrap_or(self.proxies())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Only peers advertising the same cluster name are used.
+    pub cluster: Option<String>,
+    /// Free-form location label; peers sharing it are preferred over remote ones.
+    pub site: Option<String>,
+    /// Interfaces to advertise and browse on. Empty means every interface.
+    pub allow_interfaces: Vec<String>,
+    pub deny_interfaces: Vec<String>,
+    pub address_family: AddressFamily,
+}
+impl DiscoveryConfig {
+    pub fn is_interface_allowed(&self, name: &str) -> bool {
+        (self.allow_interfaces.is_empty() || self.allow_interfaces.iter().any(|i| i == name))
+            && !self.deny_interfaces.iter().any(|i| i == name)
+    }
+    pub fn restricts_interfaces(&self) -> bool {
+        !self.allow_interfaces.is_empty() || !self.deny_interfaces.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipConfig {
+    /// Off by default, since `/gossip` lets anyone allowed to reach it add
+    /// members.
+    pub enabled: bool,
+    /// Address other nodes should use to reach this node. Defaults to the hostname.
+    pub address: Option<String>,
+    /// Nodes to gossip with in addition to the ones found through mDNS, as `host` or `host:port`.
+    pub seeds: Vec<String>,
+    pub interval_secs: u64,
+    pub failure_timeout_secs: u64,
+}
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: None,
+            seeds: Vec::new(),
+            interval_secs: 5,
+            failure_timeout_secs: 30,
+        }
+    }
+}
+
+/// Where `/proxy` sends pacman when no peer has a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyMode {
+    /// Redirects to the upstream mirrors of the pacman configuration.
+    #[default]
+    Full,
+    /// Answers 404, for a `CacheServer` entry: pacman then goes on with its
+    /// own mirrors, and no upstream mirror is checked.
+    PeersOnly,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub mode: ProxyMode,
+    /// Whether to race upstream against peers that are slow to confirm a file.
+    /// Off by default, as it sends pacman upstream for files peers hold.
+    pub hedge: bool,
+    /// How long peers get to confirm a file before upstream is tried in parallel.
+    pub hedge_delay_ms: u64,
+    /// Whether only one node of the LAN fetches a file every peer misses.
+    /// Off by default, as the other nodes wait for it before going upstream.
+    pub dedup: bool,
+    /// How long to wait for the fetching node before going upstream. pacman
+    /// gives up on a server that sends nothing for 10 seconds.
+    pub dedup_wait_secs: u64,
+    /// Networks besides loopback allowed to use `/proxy`.
+    pub allow_networks: Vec<IpNet>,
+    /// Interfaces whose attached networks are allowed to use `/proxy`.
+    pub allow_interfaces: Vec<String>,
+}
+impl ProxyConfig {
+    /// `interface_networks` lists the attached networks by interface name and
+    /// is only called when interfaces are allowed.
+    pub fn allows_client(
+        &self,
+        ip: IpAddr,
+        interface_networks: impl FnOnce() -> Vec<(String, IpNet)>,
+    ) -> bool {
+        ip.to_canonical().is_loopback()
+            || is_listed(
+                ip,
+                &self.allow_networks,
+                &self.allow_interfaces,
+                interface_networks,
+            )
+    }
+    /// Upstream is never raced in peers-only mode.
+    pub fn hedge_delay(&self) -> Option<Duration> {
+        (self.hedge && self.mode == ProxyMode::Full)
+            .then(|| Duration::from_millis(self.hedge_delay_ms))
+    }
+    pub fn dedup_wait(&self) -> Option<Duration> {
+        self.dedup
+            .then(|| Duration::from_secs(self.dedup_wait_secs))
+    }
+}
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            mode: ProxyMode::Full,
+            hedge: false,
+            hedge_delay_ms: 200,
+            dedup: false,
+            dedup_wait_secs: 6,
+            allow_networks: Vec::new(),
+            allow_interfaces: Vec::new(),
+        }
+    }
+}
+
+/// The `[proxy]` section in use, replaced as a whole when the configuration is
+/// reloaded. The other sections are only read at startup.
+#[derive(Debug)]
+pub struct LiveProxyConfig {
+    current: RwLock<Arc<ProxyConfig>>,
+}
+impl LiveProxyConfig {
+    pub fn new(config: ProxyConfig) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+    pub fn current(&self) -> Arc<ProxyConfig> {
+        self.current.read().unwrap().clone()
+    }
+    /// Returns whether the section changed.
+    pub fn replace(&self, config: ProxyConfig) -> bool {
+        let mut current = self.current.write().unwrap();
+        if **current == config {
+            return false;
+        }
+        *current = Arc::new(config);
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParentConfig {
+    /// Act as the parent cache of other nodes, pulling packages from upstream into the cache.
+    pub enabled: bool,
+    /// Parent cache to use, as `host` or `host:port`.
+    pub address: Option<String>,
+    /// Whether to use parent caches advertising themselves over mDNS.
+    pub discover: bool,
+}
+impl Default for ParentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: None,
+            discover: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Listing {
+    #[default]
+    On,
+    Off,
+    /// Only for loopback and `listing_networks`.
+    Restricted,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Networks allowed to use `/cache` and the other endpoints for peers.
+    /// Together with `allow_interfaces` empty, everyone is allowed.
+    pub allow_networks: Vec<IpNet>,
+    pub allow_interfaces: Vec<String>,
+    pub listing: Listing,
+    pub listing_networks: Vec<IpNet>,
+    /// Glob patterns of packages that are never shared, e.g. `"*-proprietary-*"`.
+    pub exclude: Vec<String>,
+}
+impl CacheConfig {
+    pub fn allows_client(
+        &self,
+        ip: IpAddr,
+        interface_networks: impl FnOnce() -> Vec<(String, IpNet)>,
+    ) -> bool {
+        (self.allow_networks.is_empty() && self.allow_interfaces.is_empty())
+            || ip.to_canonical().is_loopback()
+            || is_listed(
+                ip,
+                &self.allow_networks,
+                &self.allow_interfaces,
+                interface_networks,
+            )
+    }
+    pub fn allows_listing(&self, ip: IpAddr) -> bool {
+        match self.listing {
+            Listing::On => true,
+            Listing::Off => false,
+            Listing::Restricted => {
+                let ip = ip.to_canonical();
+                ip.is_loopback()
+                    || self
+                        .listing_networks
+                        .iter()
+                        .any(|network| network.contains(&ip))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trust {
+    /// Pins the first key seen for each host.
+    #[default]
+    Tofu,
+    /// Only accepts `trusted_keys`.
+    Explicit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Sign requests between peers and only use peers with a trusted host key.
+    pub enabled: bool,
+    /// Ed25519 secret key of this node, generated when missing.
+    pub key_file: PathBuf,
+    /// Keys pinned on first use, one `host key` pair per line.
+    pub known_hosts_file: PathBuf,
+    pub trust: Trust,
+    /// Public keys, as advertised in the `pk` TXT record, trusted for any host.
+    pub trusted_keys: Vec<String>,
+}
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key_file: PathBuf::from("/var/lib/cacheman/host_key"),
+            known_hosts_file: PathBuf::from("/var/lib/cacheman/known_hosts"),
+            trust: Trust::Tofu,
+            trusted_keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Also serve over TLS, with a self-signed certificate generated when missing.
+    pub enabled: bool,
+    pub port: u16,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// SHA-256 fingerprints of peer certificates trusted for any host, in
+    /// addition to the ones peers publish through discovery.
+    pub trusted_fingerprints: Vec<String>,
+}
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 1053,
+            cert_file: PathBuf::from("/var/lib/cacheman/tls.crt"),
+            key_file: PathBuf::from("/var/lib/cacheman/tls.key"),
+            trusted_fingerprints: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IoPriority {
+    #[default]
+    Normal,
+    /// Only uses the disk when nothing else does.
+    Idle,
+}
+
+/// Upload limits, all unlimited when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadLimits {
+    /// Total upload rate of `/cache` in KiB/s.
+    pub max_rate_kib: Option<u64>,
+    /// Upload rate to a single client in KiB/s.
+    pub max_rate_per_peer_kib: Option<u64>,
+    /// Concurrent downloads from `/cache`.
+    pub max_transfers: Option<usize>,
+}
+
+/// Limits applying between `from` and `to` local time, which may wrap past
+/// midnight, in place of the default ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadWindow {
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+    #[serde(flatten)]
+    pub limits: UploadLimits,
+}
+impl UploadWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    #[serde(flatten)]
+    pub limits: UploadLimits,
+    pub io_priority: IoPriority,
+    pub schedule: Vec<UploadWindow>,
+}
+impl UploadConfig {
+    /// The limits of the first window containing the time, or the default ones.
+    pub fn limits_at(&self, time: NaiveTime) -> UploadLimits {
+        self.schedule
+            .iter()
+            .find(|window| window.contains(time))
+            .map_or(self.limits, |window| window.limits)
+    }
+}
+
+/// When to stop advertising, browsing and serving the cache, according to the
+/// connections NetworkManager reports.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub pause_when_metered: bool,
+    /// IDs of connections on which to pause.
+    pub untrusted_connections: Vec<String>,
+    /// When set, pause unless one of these connections is active.
+    pub trusted_connections: Vec<String>,
+}
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            pause_when_metered: true,
+            untrusted_connections: Vec::new(),
+            trusted_connections: Vec::new(),
+        }
+    }
+}
+impl NetworkConfig {
+    pub fn has_policy(&self) -> bool {
+        self.pause_when_metered
+            || !self.untrusted_connections.is_empty()
+            || !self.trusted_connections.is_empty()
+    }
+    pub fn is_untrusted(&self, active_connections: &[String]) -> bool {
+        let untrusted = active_connections
+            .iter()
+            .any(|id| self.untrusted_connections.contains(id));
+        let trusted = self.trusted_connections.is_empty()
+            || active_connections
+                .iter()
+                .any(|id| self.trusted_connections.contains(id));
+        untrusted || !trusted
+    }
+}
+
+/// When to stop advertising and serving the cache, according to the battery
+/// state UPower reports.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+    pub pause_on_battery: bool,
+    /// Keep serving on battery until the charge drops below this percentage.
+    pub min_battery_percent: Option<u8>,
+}
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            pause_on_battery: true,
+            min_battery_percent: None,
+        }
+    }
+}
+impl PowerConfig {
+    /// Once paused, the node only resumes on AC power, so that the charge
+    /// hovering around the threshold does not make it come and go.
+    pub fn pauses(&self, on_battery: bool, percentage: f64, paused: bool) -> bool {
+        self.pause_on_battery
+            && on_battery
+            && (paused
+                || self
+                    .min_battery_percent
+                    .is_none_or(|min| percentage < f64::from(min)))
+    }
+}
+
+/// Whether the address is in one of the networks or in a network attached to
+/// one of the interfaces.
+fn is_listed(
+    ip: IpAddr,
+    networks: &[IpNet],
+    interfaces: &[String],
+    interface_networks: impl FnOnce() -> Vec<(String, IpNet)>,
+) -> bool {
+    let ip = ip.to_canonical();
+    if networks.iter().any(|network| network.contains(&ip)) {
+        return true;
+    }
+    !interfaces.is_empty()
+        && interface_networks()
+            .iter()
+            .any(|(name, network)| interfaces.contains(name) && network.contains(&ip))
+}
+
+pub async fn load_config(config_file_path: Option<&Path>) -> Result<Config> {
+    let path = config_file_path.unwrap_or(Path::new(DEFAULT_CONFIG_FILE_PATH));
+    let content = match read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound && config_file_path.is_none() => {
+            return Ok(Config::default());
+        }
+        Err(e) => {
+            return Err(e).context(format!("Failed to read {}", path.display()));
+        }
+    };
+    let config: Config =
+        toml::from_str(&content).context(format!("Failed to parse {}", path.display()))?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use indoc::indoc;
+
+    use crate::{
+        config::{
+            AddressFamily, AuthConfig, CacheConfig, Config, DiscoveryConfig, GossipConfig,
+            LiveProxyConfig, NetworkConfig, NodeConfig, ParentConfig, PowerConfig, ProxyConfig,
+            ProxyMode, TlsConfig, Trust, UploadLimits, load_config,
+        },
+        test_utils::generate_config_file,
+    };
+
+    #[tokio::test]
+    async fn empty_config() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file("").await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(config, Config::default());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn gossip_config() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [gossip]
+            address = "gateway.example.com"
+            seeds = ["10.0.1.1", "10.0.2.1:1052"]
+            interval_secs = 2
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(
+            config.gossip,
+            GossipConfig {
+                address: Some("gateway.example.com".to_string()),
+                seeds: vec!["10.0.1.1".to_string(), "10.0.2.1:1052".to_string()],
+                interval_secs: 2,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn proxy_config() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [proxy]
+            hedge = true
+            hedge_delay_ms = 50
+            "
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(config.proxy.hedge_delay(), Some(Duration::from_millis(50)));
+
+        // Hedging and deduplication are off unless asked for
+        let (_d, config_file_path) = generate_config_file("").await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(config.proxy.hedge_delay(), None);
+        assert_eq!(config.proxy.dedup_wait(), None);
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [proxy]
+            mode = "peers-only"
+            dedup = true
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(config.proxy.mode, ProxyMode::PeersOnly);
+        assert_eq!(config.proxy.hedge_delay(), None);
+        assert!(config.proxy.dedup_wait().is_some());
+        assert!(!config.uses_upstream());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn zero_max_transfers() -> Result<()> {
+        for content in [
+            "[upload]\nmax_transfers = 0\n",
+            "[[upload.schedule]]\nfrom = \"09:00\"\nto = \"17:00\"\nmax_transfers = 0\n",
+        ] {
+            let (_d, config_file_path) = generate_config_file(content).await?;
+            assert!(load_config(Some(&config_file_path)).await.is_err());
+        }
+        Ok(())
+    }
+    #[test]
+    fn live_proxy_config_replace() {
+        let proxy = LiveProxyConfig::new(ProxyConfig::default());
+        let before = proxy.current();
+        assert!(!proxy.replace(ProxyConfig::default()));
+        let peers_only = ProxyConfig {
+            mode: ProxyMode::PeersOnly,
+            ..Default::default()
+        };
+        assert!(proxy.replace(peers_only.clone()));
+        assert_eq!(*proxy.current(), peers_only);
+        assert_eq!(before.mode, ProxyMode::Full);
+    }
+    #[tokio::test]
+    async fn proxy_clients() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [proxy]
+            allow_networks = ["10.0.0.0/8", "fd00::/8"]
+            allow_interfaces = ["virbr0"]
+            "#
+        ))
+        .await?;
+        let proxy = load_config(Some(&config_file_path)).await?.proxy;
+        let interfaces = || {
+            vec![
+                ("eth0".to_string(), "192.168.1.0/24".parse().unwrap()),
+                ("virbr0".to_string(), "192.168.122.0/24".parse().unwrap()),
+            ]
+        };
+        let allows = |ip: &str| proxy.allows_client(ip.parse().unwrap(), interfaces);
+        assert!(allows("127.0.0.1"));
+        assert!(allows("::ffff:127.0.0.1"));
+        assert!(allows("10.1.2.3"));
+        assert!(allows("fd00::1"));
+        assert!(allows("192.168.122.10"));
+        assert!(!allows("192.168.1.10"));
+        assert!(!allows("172.16.0.1"));
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [proxy]
+            allow_networks = ["10.0.0.1"]
+            "#
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cache_access() -> Result<()> {
+        let open = CacheConfig::default();
+        assert!(open.allows_client("203.0.113.1".parse()?, Vec::new));
+        assert!(open.allows_listing("203.0.113.1".parse()?));
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [cache]
+            allow_networks = ["192.168.1.0/24"]
+            listing = "restricted"
+            listing_networks = ["192.168.1.10/32"]
+            exclude = ["*-proprietary-*"]
+            "#
+        ))
+        .await?;
+        let cache = load_config(Some(&config_file_path)).await?.cache;
+        assert!(cache.allows_client("192.168.1.20".parse()?, Vec::new));
+        assert!(cache.allows_client("127.0.0.1".parse()?, Vec::new));
+        assert!(!cache.allows_client("203.0.113.1".parse()?, Vec::new));
+        assert!(cache.allows_listing("192.168.1.10".parse()?));
+        assert!(!cache.allows_listing("192.168.1.20".parse()?));
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [cache]
+            exclude = ["[unclosed"]
+            "#
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn parent_config() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [parent]
+            address = "cachebox"
+            discover = false
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(
+            config.parent,
+            ParentConfig {
+                enabled: false,
+                address: Some("cachebox".to_string()),
+                discover: false,
+            }
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn auth_config() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [auth]
+            enabled = true
+            trust = "explicit"
+            trusted_keys = ["wbK2dcnyeL4zwQHNOla_rO6IHKgRilvaQWicRsFeDw4"]
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(
+            config.auth,
+            AuthConfig {
+                enabled: true,
+                trust: Trust::Explicit,
+                trusted_keys: vec!["wbK2dcnyeL4zwQHNOla_rO6IHKgRilvaQWicRsFeDw4".to_string()],
+                ..Default::default()
+            }
+        );
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [auth]
+            trusted_keys = ["not a key"]
+            "#
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn tls_config() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [tls]
+            enabled = true
+            port = 8443
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(
+            config.tls,
+            TlsConfig {
+                enabled: true,
+                port: 8443,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+    #[test]
+    fn untrusted_connections() {
+        let active = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let config = NetworkConfig {
+            untrusted_connections: vec!["Hotspot".to_string()],
+            ..Default::default()
+        };
+        assert!(!config.is_untrusted(&active(&["Home"])));
+        assert!(!config.is_untrusted(&active(&[])));
+        assert!(config.is_untrusted(&active(&["Home", "Hotspot"])));
+        let config = NetworkConfig {
+            trusted_connections: vec!["Home".to_string(), "Office".to_string()],
+            ..Default::default()
+        };
+        assert!(!config.is_untrusted(&active(&["Office"])));
+        assert!(config.is_untrusted(&active(&["Cafe"])));
+        assert!(config.is_untrusted(&active(&[])));
+    }
+    #[test]
+    fn battery_threshold() {
+        let config = PowerConfig::default();
+        assert!(config.pauses(true, 100.0, false));
+        assert!(!config.pauses(false, 5.0, true));
+        let config = PowerConfig {
+            min_battery_percent: Some(40),
+            ..Default::default()
+        };
+        assert!(!config.pauses(true, 80.0, false));
+        assert!(config.pauses(true, 39.0, false));
+        // Charging back above the threshold on battery is not enough
+        assert!(config.pauses(true, 41.0, true));
+        assert!(!config.pauses(false, 41.0, true));
+        let config = PowerConfig {
+            pause_on_battery: false,
+            ..Default::default()
+        };
+        assert!(!config.pauses(true, 1.0, false));
+    }
+    #[tokio::test]
+    async fn upload_config() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [upload]
+            max_rate_kib = 10240
+            max_transfers = 4
+            io_priority = "idle"
+
+            [[upload.schedule]]
+            from = "09:00"
+            to = "18:00"
+            max_rate_kib = 1024
+            max_rate_per_peer_kib = 512
+
+            [[upload.schedule]]
+            from = "22:00"
+            to = "06:00"
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        let upload = config.upload;
+        let at = |time: &str| upload.limits_at(time.parse().unwrap());
+        assert_eq!(
+            at("08:59"),
+            UploadLimits {
+                max_rate_kib: Some(10240),
+                max_rate_per_peer_kib: None,
+                max_transfers: Some(4),
+            }
+        );
+        assert_eq!(
+            at("12:00"),
+            UploadLimits {
+                max_rate_kib: Some(1024),
+                max_rate_per_peer_kib: Some(512),
+                max_transfers: None,
+            }
+        );
+        assert_eq!(at("23:00"), UploadLimits::default());
+        assert_eq!(at("05:59"), UploadLimits::default());
+        assert_eq!(at("18:00").max_rate_kib, Some(10240));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn roles() -> Result<()> {
+        let node = NodeConfig::default();
+        assert!(node.advertises() && node.serves_cache() && node.proxies() && node.browses());
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [node]
+            role = "client"
+            "#
+        ))
+        .await?;
+        let node = load_config(Some(&config_file_path)).await?.node;
+        assert!(!node.advertises() && !node.serves_cache());
+        assert!(node.proxies() && node.browses());
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [node]
+            role = "server"
+            advertise = false
+            "#
+        ))
+        .await?;
+        let node = load_config(Some(&config_file_path)).await?.node;
+        assert!(!node.advertises() && node.serves_cache());
+        assert!(!node.proxies() && !node.browses());
+
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [node]
+            role = "client"
+            proxy = false
+            "#
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn cluster() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [discovery]
+            cluster = "build-farm"
+            site = "office-2f"
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(
+            config.discovery,
+            DiscoveryConfig {
+                cluster: Some("build-farm".to_string()),
+                site: Some("office-2f".to_string()),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn interfaces() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [discovery]
+            deny_interfaces = ["docker0", "tun0"]
+            address_family = "ipv4"
+            "#
+        ))
+        .await?;
+        let config = load_config(Some(&config_file_path)).await?;
+        assert_eq!(config.discovery.address_family, AddressFamily::Ipv4);
+        assert!(config.discovery.restricts_interfaces());
+        assert!(config.discovery.is_interface_allowed("eth0"));
+        assert!(!config.discovery.is_interface_allowed("docker0"));
+
+        let discovery = DiscoveryConfig {
+            allow_interfaces: vec!["eth0".to_string(), "wlan0".to_string()],
+            deny_interfaces: vec!["wlan0".to_string()],
+            ..Default::default()
+        };
+        assert!(discovery.is_interface_allowed("eth0"));
+        assert!(!discovery.is_interface_allowed("wlan0"));
+        assert!(!discovery.is_interface_allowed("docker0"));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn invalid_cluster() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            r#"
+            [discovery]
+            cluster = "build.farm"
+            "#
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn zero_gossip_interval() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [gossip]
+            enabled = true
+            interval_secs = 0
+            "
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_err());
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [gossip]
+            interval_secs = 0
+            "
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_ok());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn unknown_key() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [gossip]
+            unknown = 1
+            "
+        ))
+        .await?;
+        assert!(load_config(Some(&config_file_path)).await.is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn missing_explicit_file() -> Result<()> {
+        let (d, _) = generate_config_file("").await?;
+        let path = d.path().join("missing.toml");
+        assert!(load_config(Some(&path)).await.is_err());
+        Ok(())
+    }
+}
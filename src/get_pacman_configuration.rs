@@ -1,40 +1,420 @@
-use std::path::Path;
-
-use anyhow::{Result, ensure};
-use tokio::process::Command;
-
-pub mod cache_dir;
-pub mod upstream_url;
-
-pub async fn pacman_conf(
-    config_file_path: Option<&Path>,
-    args: impl IntoIterator<Item = &str>,
-) -> Result<String> {
-    let mut command = Command::new("pacman-conf");
-    command.args(args);
-    if let Some(config_file_path) = config_file_path {
-        command.arg("--config").arg(config_file_path);
-    }
-    let output = command.output().await?;
-    ensure!(
-        output.status.success(),
-        "pacman-conf failed: {}",
-        String::from_utf8_lossy(&output.stderr),
-    );
-    let output = String::from_utf8(output.stdout)?;
-    Ok(output)
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use glob::glob;
+use log::warn;
+use nix::sys::utsname::uname;
+use tokio::task::spawn_blocking;
+
+const DEFAULT_CONFIG_FILE_PATH: &str = "/etc/pacman.conf";
+const DEFAULT_CACHE_DIR: &str = "/var/cache/pacman/pkg";
+/// pacman gives up on includes nested deeper than this.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+const SIG_LEVELS: [&str; 5] = ["Never", "Optional", "Required", "TrustedOnly", "TrustAll"];
+const USAGES: [&str; 5] = ["Sync", "Search", "Install", "Upgrade", "All"];
+
+/// What cacheman takes from `pacman.conf`, read the way pacman reads it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacmanConf {
+    pub cache_dirs: Vec<PathBuf>,
+    /// Servers of each repository, with `$repo` and `$arch` expanded.
+    pub repositories: HashMap<String, Vec<String>>,
+    /// Cache servers of each repository, expanded the same way. They are
+    /// usually LAN caches such as cacheman itself, not upstreams.
+    pub cache_servers: HashMap<String, Vec<String>>,
+    /// Files included in each repository section, such as its mirrorlist.
+    pub includes: HashMap<String, Vec<PathBuf>>,
+}
+
+pub async fn pacman_conf(config_file_path: Option<&Path>) -> Result<PacmanConf> {
+    let path = config_file_path
+        .unwrap_or(Path::new(DEFAULT_CONFIG_FILE_PATH))
+        .to_path_buf();
+    spawn_blocking(move || {
+        let mut parser = Parser::default();
+        parser.parse_file(&path, 0)?;
+        parser.finish()
+    })
+    .await?
+}
+
+#[derive(Debug, Default)]
+struct Parser {
+    section: Option<String>,
+    cache_dirs: Vec<PathBuf>,
+    architectures: Vec<String>,
+    /// Servers as written, expanded once the architecture is known.
+    repositories: Vec<(String, Vec<String>)>,
+    cache_servers: HashMap<String, Vec<String>>,
+    includes: HashMap<String, Vec<PathBuf>>,
+}
+impl Parser {
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        ensure!(
+            depth < MAX_INCLUDE_DEPTH,
+            "{}: too many nested includes",
+            path.display()
+        );
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        for (number, line) in content.lines().enumerate() {
+            self.parse_line(line, depth)
+                .with_context(|| format!("{}:{}", path.display(), number + 1))?;
+        }
+        Ok(())
+    }
+    fn parse_line(&mut self, line: &str, depth: usize) -> Result<()> {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            ensure!(!name.is_empty(), "Empty section name");
+            if name != "options" {
+                ensure!(
+                    self.repositories.iter().all(|(repo, _)| repo != name),
+                    "Repository {name} is declared twice"
+                );
+                self.repositories.push((name.to_string(), Vec::new()));
+            }
+            self.section = Some(name.to_string());
+            return Ok(());
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+        let Some(section) = &self.section else {
+            bail!("{key} is outside of any section");
+        };
+        let in_options = section == "options";
+        let repository = (!in_options).then(|| section.clone());
+        let value = || {
+            value
+                .filter(|value| !value.is_empty())
+                .with_context(|| format!("{key} needs a value"))
+        };
+        match key {
+            "Include" => {
+                let pattern = value()?;
+                let mut included = false;
+                for path in glob(pattern)? {
+                    let path = path?;
+                    self.parse_file(&path, depth + 1)?;
+                    if let Some(repository) = &repository {
+                        self.includes
+                            .entry(repository.clone())
+                            .or_default()
+                            .push(path);
+                    }
+                    included = true;
+                }
+                if !included {
+                    warn!("No file matches the pacman include {pattern}");
+                }
+            }
+            "CacheDir" if in_options => {
+                self.cache_dirs
+                    .extend(value()?.split_whitespace().map(PathBuf::from));
+            }
+            "Architecture" if in_options => {
+                self.architectures
+                    .extend(value()?.split_whitespace().map(str::to_string));
+            }
+            "Server" | "CacheServer" | "Usage" if in_options => {
+                bail!("{key} is only valid in a repository section");
+            }
+            "Server" => {
+                let url = value()?.to_string();
+                if let Some((_, servers)) = self.repositories.last_mut() {
+                    servers.push(url);
+                }
+            }
+            "CacheServer" => {
+                let url = value()?.to_string();
+                if let Some(repository) = repository {
+                    self.cache_servers.entry(repository).or_default().push(url);
+                }
+            }
+            "SigLevel" => {
+                for level in value()?.split_whitespace() {
+                    let level = level
+                        .strip_prefix("Package")
+                        .or_else(|| level.strip_prefix("Database"))
+                        .unwrap_or(level);
+                    ensure!(SIG_LEVELS.contains(&level), "Invalid SigLevel {level}");
+                }
+            }
+            "Usage" => {
+                for usage in value()?.split_whitespace() {
+                    ensure!(USAGES.contains(&usage), "Invalid Usage {usage}");
+                }
+            }
+            // The other options do not concern cacheman
+            _ => {}
+        }
+        Ok(())
+    }
+    fn finish(self) -> Result<PacmanConf> {
+        let cache_dirs = if self.cache_dirs.is_empty() {
+            vec![PathBuf::from(DEFAULT_CACHE_DIR)]
+        } else {
+            self.cache_dirs
+        };
+        // $arch stands for the first architecture, like in pacman
+        let architecture = match self.architectures.first().map(String::as_str) {
+            Some("auto") => Some(uname()?.machine().to_string_lossy().into_owned()),
+            architecture => architecture.map(str::to_string),
+        };
+        let expand = |name: &str, servers: Vec<String>| {
+            servers
+                .into_iter()
+                .map(|server| {
+                    let server = server.replace("$repo", name);
+                    if !server.contains("$arch") {
+                        return Ok(server);
+                    }
+                    let architecture = architecture.as_deref().with_context(|| {
+                        format!("{server} uses $arch, but no Architecture is set")
+                    })?;
+                    Ok(server.replace("$arch", architecture))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let mut repositories = HashMap::new();
+        for (name, servers) in self.repositories {
+            let servers = expand(&name, servers)?;
+            repositories.insert(name, servers);
+        }
+        let mut cache_servers = HashMap::new();
+        for (name, servers) in self.cache_servers {
+            let servers = expand(&name, servers)?;
+            cache_servers.insert(name, servers);
+        }
+        Ok(PacmanConf {
+            cache_dirs,
+            repositories,
+            cache_servers,
+            includes: self.includes,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
     use anyhow::Result;
+    use indoc::{formatdoc, indoc};
+    use tokio::fs::{create_dir, write};
 
-    use crate::get_pacman_configuration::pacman_conf;
+    use crate::{get_pacman_configuration::pacman_conf, test_utils::generate_config_file};
+
+    #[tokio::test]
+    async fn default_cache_dir() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [options]
+            "
+        ))
+        .await?;
+        let output = pacman_conf(Some(&config_file_path)).await?;
+        assert_eq!(
+            output.cache_dirs,
+            vec![PathBuf::from("/var/cache/pacman/pkg")]
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn custom_cache_dir() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [options]
+            CacheDir = /tmp/pacman-cache
+            "
+        ))
+        .await?;
+        let output = pacman_conf(Some(&config_file_path)).await?;
+        assert_eq!(output.cache_dirs, vec![PathBuf::from("/tmp/pacman-cache")]);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn multiple_cache_dirs() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [options]
+            CacheDir = /var/cache/pacman/pkg
+            CacheDir = /tmp/pacman-cache
+            "
+        ))
+        .await?;
+        let output = pacman_conf(Some(&config_file_path)).await?;
+        assert_eq!(
+            output.cache_dirs,
+            vec![
+                PathBuf::from("/var/cache/pacman/pkg"),
+                PathBuf::from("/tmp/pacman-cache")
+            ]
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_get_repositories() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [options]
+            Architecture = auto
+            [core]
+            Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+            "
+        ))
+        .await?;
+        let repositories = pacman_conf(Some(&config_file_path)).await?.repositories;
+        assert_eq!(repositories.into_keys().collect::<Vec<_>>(), vec!["core"]);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_get_urls_from_repository() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [options]
+            Architecture = auto
+            [core]
+            Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+            "
+        ))
+        .await?;
+        let urls = pacman_conf(Some(&config_file_path)).await?.repositories;
+        assert_eq!(
+            urls["core"],
+            vec!["https://geo.mirror.pkgbuild.com/core/os/x86_64"]
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_get_urls_from_repository_not_found() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [options]
+            Architecture = auto
+            [core]
+            Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+            [extra]
+            Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+            [multilib]
+            Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+            "
+        ))
+        .await?;
+        let list_1 = [
+            ("core", "https://geo.mirror.pkgbuild.com/core/os/x86_64"),
+            ("extra", "https://geo.mirror.pkgbuild.com/extra/os/x86_64"),
+            (
+                "multilib",
+                "https://geo.mirror.pkgbuild.com/multilib/os/x86_64",
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+        .collect::<HashMap<_, _>>();
+        assert_eq!(
+            pacman_conf(Some(&config_file_path)).await?.repositories,
+            list_1
+        );
 
+        Ok(())
+    }
     #[tokio::test]
     async fn fail() -> Result<()> {
-        let output = pacman_conf(None, ["--invalid-option"]).await;
-        assert!(output.is_err(), "Expected error, but got: {:?}", output);
+        for content in [
+            "CacheDir = /tmp\n",
+            "[options]\n[core]\nSigLevel = Sometimes\n",
+            "[options]\nServer = https://example.com\n",
+            "[core]\nServer = https://example.com/$arch\n",
+        ] {
+            let (_d, config_file_path) = generate_config_file(content).await?;
+            let output = pacman_conf(Some(&config_file_path)).await;
+            assert!(output.is_err(), "Expected error, but got: {:?}", output);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn include_globs() -> Result<()> {
+        let (d, config_file_path) = generate_config_file("").await?;
+        let include_dir = d.path().join("pacman.d");
+        create_dir(&include_dir).await?;
+        write(
+            include_dir.join("b.conf"),
+            "Server = https://b.example.com/$repo/os/$arch\n",
+        )
+        .await?;
+        write(
+            include_dir.join("a.conf"),
+            "Server = https://a.example.com/$repo/os/$arch\n",
+        )
+        .await?;
+        write(
+            &config_file_path,
+            formatdoc!(
+                "
+                [options]
+                Architecture = x86_64_v3 x86_64
+                SigLevel = Required DatabaseOptional
+                [core-testing]
+                Usage = Sync Search
+                CacheServer = http://localhost:1052/proxy/$repo/os/$arch
+                Include = {}/*.conf # mirrors
+                ",
+                include_dir.display()
+            ),
+        )
+        .await?;
+        let output = pacman_conf(Some(&config_file_path)).await?;
+        assert_eq!(
+            output.repositories,
+            HashMap::from([(
+                "core-testing".to_string(),
+                vec![
+                    "https://a.example.com/core-testing/os/x86_64_v3".to_string(),
+                    "https://b.example.com/core-testing/os/x86_64_v3".to_string(),
+                ]
+            )])
+        );
+        assert_eq!(
+            output.cache_servers,
+            HashMap::from([(
+                "core-testing".to_string(),
+                vec!["http://localhost:1052/proxy/core-testing/os/x86_64_v3".to_string()]
+            )])
+        );
+        assert_eq!(
+            output.includes["core-testing"],
+            vec![include_dir.join("a.conf"), include_dir.join("b.conf")]
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn options_without_value() -> Result<()> {
+        let (_d, config_file_path) = generate_config_file(indoc!(
+            "
+            [options]
+            Color
+            ILoveCandy
+            Architecture = auto
+            [extra]
+            "
+        ))
+        .await?;
+        let output = pacman_conf(Some(&config_file_path)).await?;
+        assert_eq!(
+            output.repositories,
+            HashMap::from([("extra".to_string(), Vec::new())])
+        );
         Ok(())
     }
 }
@@ -0,0 +1,401 @@
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    error::{ErrorBadRequest, ErrorForbidden, ErrorServiceUnavailable},
+    post, web,
+};
+use anyhow::{Context, Result, ensure};
+use log::debug;
+use membership::{Member, Membership, MembershipChanges};
+use rand::{rng, seq::IndexedRandom};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use tokio::{select, spawn, time::interval};
+
+use crate::{
+    CLIENT, PORT,
+    activity::{Activity, Scope},
+    auth::Auth,
+    cache_policy::CachePolicy,
+    config::{DiscoveryConfig, GossipConfig},
+    peer_registry::{Peer, PeerRegistry, PeerSource, authority},
+    tls::TlsEndpoint,
+};
+
+pub mod membership;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipMessage {
+    #[serde(default)]
+    pub cluster: Option<String>,
+    pub members: Vec<Member>,
+}
+
+pub struct Gossip {
+    cluster: Option<String>,
+    /// Whether other nodes should use this one as a peer.
+    announce: bool,
+    membership: Mutex<Membership>,
+    seeds: Vec<(String, u16)>,
+    auth: web::Data<Auth>,
+    peer_registry: web::Data<PeerRegistry>,
+}
+impl Gossip {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &GossipConfig,
+        discovery: &DiscoveryConfig,
+        address: String,
+        port: u16,
+        announce: bool,
+        tls: Option<TlsEndpoint>,
+        auth: web::Data<Auth>,
+        peer_registry: web::Data<PeerRegistry>,
+    ) -> Result<Self> {
+        let seeds = config
+            .seeds
+            .iter()
+            .map(|seed| parse_seed(seed))
+            .collect::<Result<Vec<_>>>()?;
+        let failure_timeout = Duration::from_secs(config.failure_timeout_secs);
+        Ok(Self {
+            cluster: discovery.cluster.clone(),
+            announce,
+            membership: Mutex::new(Membership::new(
+                address,
+                port,
+                discovery.site.clone(),
+                auth.public_key(),
+                tls,
+                failure_timeout,
+            )),
+            seeds,
+            auth,
+            peer_registry,
+        })
+    }
+    fn apply(&self, changes: MembershipChanges) {
+        // A member dropped from the registry, say after failing a probe, comes
+        // back once it is heard from again
+        let returned = changes
+            .alive
+            .into_iter()
+            .filter(|(address, _)| self.peer_registry.host(address).is_none());
+        for (address, port) in changes.joined.into_iter().chain(returned) {
+            debug!("Gossip member joined: {address}:{port}");
+            let (site, public_key, tls) = {
+                let membership = self.membership.lock().unwrap();
+                (
+                    membership.site(&address),
+                    membership.public_key(&address),
+                    membership.tls(&address),
+                )
+            };
+            if !self.auth.check_host(&address, public_key.as_deref()) {
+                debug!("Ignoring gossip member {address} without a trusted key");
+                continue;
+            }
+            let peer = Peer {
+                host: address.clone(),
+                port,
+                source: PeerSource::Gossip,
+                addresses: address.parse().into_iter().collect(),
+                site,
+                tls,
+            };
+            self.peer_registry.insert(address, peer);
+        }
+        for address in changes.failed {
+            debug!("Gossip member failed: {address}");
+            self.peer_registry.remove_from(&address, PeerSource::Gossip);
+        }
+    }
+    fn digest(&self, membership: &Membership) -> Vec<Member> {
+        let mut digest = membership.digest();
+        if !self.announce {
+            digest.retain(|member| member.address != membership.own_address());
+        }
+        digest
+    }
+    pub fn exchange(&self, incoming: Vec<Member>) -> Vec<Member> {
+        let mut membership = self.membership.lock().unwrap();
+        let changes = membership.merge(incoming, Instant::now());
+        let digest = self.digest(&membership);
+        drop(membership);
+        self.apply(changes);
+        digest
+    }
+    /// Returns the name of the target, the host it is reached at and its port.
+    fn pick_target(&self) -> Option<(String, String, u16)> {
+        let membership = self.membership.lock().unwrap();
+        let own_address = membership.own_address().to_string();
+        let alive_members = membership.alive_members();
+        drop(membership);
+        let candidates = alive_members
+            .into_iter()
+            .chain(self.seeds.iter().cloned())
+            .map(|(address, port)| (address.clone(), address, port))
+            .chain(
+                self.peer_registry
+                    .snapshot()
+                    .into_iter()
+                    .map(|(name, peer)| (name, peer.host, peer.port)),
+            )
+            .filter(|(name, _, _)| *name != own_address)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        candidates.choose(&mut rng()).cloned()
+    }
+    async fn round(&self) -> Result<()> {
+        let (changes, digest) = {
+            let mut membership = self.membership.lock().unwrap();
+            membership.beat();
+            (membership.expire(Instant::now()), self.digest(&membership))
+        };
+        self.apply(changes);
+
+        let Some((name, host, port)) = self.pick_target() else {
+            return Ok(());
+        };
+        let body = serde_json::to_vec(&GossipMessage {
+            cluster: self.cluster.clone(),
+            members: digest,
+        })?;
+        let url = self.auth.sign_request(
+            "POST",
+            format!("http://{}/gossip", authority(&host, port)),
+            &body,
+        );
+        let response = CLIENT
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .timeout(Duration::from_secs(3))
+            .send()
+            .await?
+            .error_for_status()?;
+        let body = self.auth.read_verified(&name, &url, response).await?;
+        let response = serde_json::from_slice::<GossipMessage>(&body)?;
+        ensure!(
+            response.cluster == self.cluster,
+            "{} belongs to another cluster",
+            name
+        );
+        self.exchange(response.members);
+        Ok(())
+    }
+    /// Gossips periodically, except while the node is paused.
+    pub fn run(gossip: web::Data<Self>, period: Duration, activity: web::Data<Activity>) {
+        spawn(async move {
+            let mut interval = interval(period);
+            loop {
+                // Peers may have come and gone while the machine slept
+                select! {
+                    _ = interval.tick() => {}
+                    _ = activity.woken() => interval.reset(),
+                }
+                if activity.is_paused(Scope::Lan) {
+                    activity.wait_until_paused(Scope::Lan, false).await;
+                    interval.reset();
+                }
+                if let Err(e) = gossip.round().await {
+                    debug!("Gossip round failed: {e:#}");
+                }
+            }
+        });
+    }
+}
+
+pub fn parse_seed(seed: &str) -> Result<(String, u16)> {
+    if let Some(rest) = seed.strip_prefix('[') {
+        let (host, port) = rest
+            .split_once(']')
+            .context(format!("Invalid gossip seed: {}", seed))?;
+        let port = match port.strip_prefix(':') {
+            Some(port) => port.parse()?,
+            None => PORT,
+        };
+        return Ok((host.to_string(), port));
+    }
+    match seed.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Ok((host.to_string(), port.parse()?)),
+        _ => Ok((seed.to_string(), PORT)),
+    }
+}
+
+#[post("/gossip")]
+async fn service_gossip(
+    body: web::Bytes,
+    gossip: web::Data<Gossip>,
+    cache_policy: web::Data<CachePolicy>,
+    activity: web::Data<Activity>,
+    http_request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    if activity.is_paused(Scope::Lan) {
+        return Err(ErrorServiceUnavailable("Paused"));
+    }
+    if !cache_policy.allows_request(&http_request) {
+        return Err(ErrorForbidden("Not allowed"));
+    }
+    let message = serde_json::from_slice::<GossipMessage>(&body).map_err(ErrorBadRequest)?;
+    if message.cluster != gossip.cluster {
+        return Err(ErrorForbidden("Different cluster"));
+    }
+    // A node gossiping for the first time introduces its own key
+    let introduced = message
+        .members
+        .iter()
+        .map(|member| (member.address.as_str(), member.public_key.as_deref()));
+    let query = http_request.query_string();
+    if !gossip.auth.authorize_introduced(
+        http_request.method().as_str(),
+        http_request.path(),
+        query,
+        &body,
+        introduced,
+    ) {
+        return Err(ErrorForbidden("Not signed by a trusted peer"));
+    }
+    let members = gossip.exchange(message.members);
+    gossip.auth.signed_json(
+        query,
+        &GossipMessage {
+            cluster: gossip.cluster.clone(),
+            members,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Data;
+    use anyhow::Result;
+
+    use crate::{
+        PORT,
+        auth::Auth,
+        config::{DiscoveryConfig, GossipConfig},
+        gossip::{Gossip, membership::Member, parse_seed},
+        peer_registry::{Peer, PeerRegistry, PeerSource},
+    };
+
+    #[test]
+    fn seeds() -> Result<()> {
+        assert_eq!(parse_seed("10.0.0.1")?, ("10.0.0.1".to_string(), PORT));
+        assert_eq!(parse_seed("host:8080")?, ("host".to_string(), 8080));
+        assert_eq!(parse_seed("[fd00::1]:8080")?, ("fd00::1".to_string(), 8080));
+        assert_eq!(parse_seed("fd00::1")?, ("fd00::1".to_string(), PORT));
+        assert!(parse_seed("host:port").is_err());
+        Ok(())
+    }
+    #[test]
+    fn exchange_updates_registry() -> Result<()> {
+        let registry = Data::new(PeerRegistry::default());
+        let gossip = Gossip::new(
+            &GossipConfig::default(),
+            &DiscoveryConfig::default(),
+            "self".to_string(),
+            PORT,
+            true,
+            None,
+            Data::new(Auth::disabled()),
+            registry.clone(),
+        )?;
+        let digest = gossip.exchange(vec![Member {
+            address: "remote".to_string(),
+            port: 8080,
+            heartbeat: 1,
+            site: Some("office".to_string()),
+            public_key: None,
+            tls: None,
+        }]);
+        assert_eq!(digest.len(), 2);
+        assert_eq!(
+            registry.snapshot(),
+            vec![(
+                "remote".to_string(),
+                Peer {
+                    host: "remote".to_string(),
+                    port: 8080,
+                    source: PeerSource::Gossip,
+                    addresses: Vec::new(),
+                    site: Some("office".to_string()),
+                    tls: None,
+                }
+            )]
+        );
+        Ok(())
+    }
+    #[test]
+    fn removed_peer_returns_with_next_heartbeat() -> Result<()> {
+        let registry = Data::new(PeerRegistry::default());
+        let gossip = Gossip::new(
+            &GossipConfig::default(),
+            &DiscoveryConfig::default(),
+            "self".to_string(),
+            PORT,
+            true,
+            None,
+            Data::new(Auth::disabled()),
+            registry.clone(),
+        )?;
+        let mut remote = Member {
+            address: "remote".to_string(),
+            port: 8080,
+            heartbeat: 1,
+            site: None,
+            public_key: None,
+            tls: None,
+        };
+        gossip.exchange(vec![remote.clone()]);
+        registry.remove("remote");
+        gossip.exchange(vec![remote.clone()]);
+        assert!(registry.snapshot().is_empty());
+        remote.heartbeat = 2;
+        gossip.exchange(vec![remote]);
+        assert_eq!(registry.host("remote").as_deref(), Some("remote"));
+        Ok(())
+    }
+    #[test]
+    fn client_is_not_announced() -> Result<()> {
+        let registry = Data::new(PeerRegistry::default());
+        let gossip = Gossip::new(
+            &GossipConfig::default(),
+            &DiscoveryConfig::default(),
+            "self".to_string(),
+            PORT,
+            false,
+            None,
+            Data::new(Auth::disabled()),
+            registry,
+        )?;
+        assert!(gossip.exchange(Vec::new()).is_empty());
+        Ok(())
+    }
+    #[test]
+    fn pick_target_excludes_self() -> Result<()> {
+        let registry = Data::new(PeerRegistry::default());
+        let config = GossipConfig {
+            seeds: vec!["self".to_string()],
+            ..Default::default()
+        };
+        let gossip = Gossip::new(
+            &config,
+            &DiscoveryConfig::default(),
+            "self".to_string(),
+            PORT,
+            true,
+            None,
+            Data::new(Auth::disabled()),
+            registry,
+        )?;
+        assert_eq!(gossip.pick_target(), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tls::TlsEndpoint;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub address: String,
+    pub port: u16,
+    pub heartbeat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+    /// Host key, when the member authenticates its requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsEndpoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemberStatus {
+    Alive,
+    Failed,
+}
+
+#[derive(Debug)]
+struct MemberState {
+    port: u16,
+    heartbeat: u64,
+    site: Option<String>,
+    public_key: Option<String>,
+    tls: Option<TlsEndpoint>,
+    updated_at: Instant,
+    status: MemberStatus,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MembershipChanges {
+    pub joined: Vec<(String, u16)>,
+    /// Known members heard from again, which the peer registry may have
+    /// dropped meanwhile.
+    pub alive: Vec<(String, u16)>,
+    pub failed: Vec<String>,
+}
+
+/// Heartbeat based membership: a member whose heartbeat has not increased for
+/// `failure_timeout` is considered failed. Until twice that, only a newer
+/// heartbeat brings it back; after that it is forgotten, and any heartbeat
+/// gossiped about it makes it join again.
+#[derive(Debug)]
+pub struct Membership {
+    own: Member,
+    members: HashMap<String, MemberState>,
+    failure_timeout: Duration,
+}
+impl Membership {
+    pub fn new(
+        address: String,
+        port: u16,
+        site: Option<String>,
+        public_key: Option<String>,
+        tls: Option<TlsEndpoint>,
+        failure_timeout: Duration,
+    ) -> Self {
+        // Starting from the wall clock keeps the heartbeat increasing across restarts
+        let heartbeat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Self {
+            own: Member {
+                address,
+                port,
+                heartbeat,
+                site,
+                public_key,
+                tls,
+            },
+            members: HashMap::new(),
+            failure_timeout,
+        }
+    }
+    pub fn own_address(&self) -> &str {
+        &self.own.address
+    }
+    pub fn beat(&mut self) {
+        self.own.heartbeat += 1;
+    }
+    pub fn digest(&self) -> Vec<Member> {
+        let mut digest = vec![self.own.clone()];
+        for (address, state) in self.members.iter() {
+            if state.status == MemberStatus::Alive {
+                digest.push(Member {
+                    address: address.clone(),
+                    port: state.port,
+                    heartbeat: state.heartbeat,
+                    site: state.site.clone(),
+                    public_key: state.public_key.clone(),
+                    tls: state.tls.clone(),
+                });
+            }
+        }
+        digest
+    }
+    pub fn alive_members(&self) -> Vec<(String, u16)> {
+        self.members
+            .iter()
+            .filter(|(_, state)| state.status == MemberStatus::Alive)
+            .map(|(address, state)| (address.clone(), state.port))
+            .collect()
+    }
+    pub fn site(&self, address: &str) -> Option<String> {
+        self.members.get(address)?.site.clone()
+    }
+    pub fn public_key(&self, address: &str) -> Option<String> {
+        self.members.get(address)?.public_key.clone()
+    }
+    pub fn tls(&self, address: &str) -> Option<TlsEndpoint> {
+        self.members.get(address)?.tls.clone()
+    }
+    pub fn merge(&mut self, incoming: Vec<Member>, now: Instant) -> MembershipChanges {
+        let mut changes = MembershipChanges::default();
+        for member in incoming {
+            if member.address == self.own.address {
+                continue;
+            }
+            match self.members.get_mut(&member.address) {
+                Some(state) if member.heartbeat <= state.heartbeat => {}
+                Some(state) => {
+                    match state.status {
+                        MemberStatus::Failed => &mut changes.joined,
+                        MemberStatus::Alive => &mut changes.alive,
+                    }
+                    .push((member.address.clone(), member.port));
+                    state.port = member.port;
+                    state.heartbeat = member.heartbeat;
+                    state.site = member.site;
+                    state.public_key = member.public_key;
+                    state.tls = member.tls;
+                    state.updated_at = now;
+                    state.status = MemberStatus::Alive;
+                }
+                None => {
+                    changes.joined.push((member.address.clone(), member.port));
+                    self.members.insert(
+                        member.address,
+                        MemberState {
+                            port: member.port,
+                            heartbeat: member.heartbeat,
+                            site: member.site,
+                            public_key: member.public_key,
+                            tls: member.tls,
+                            updated_at: now,
+                            status: MemberStatus::Alive,
+                        },
+                    );
+                }
+            }
+        }
+        changes
+    }
+    pub fn expire(&mut self, now: Instant) -> MembershipChanges {
+        let mut changes = MembershipChanges::default();
+        let failure_timeout = self.failure_timeout;
+        self.members.retain(|address, state| {
+            let elapsed = now.saturating_duration_since(state.updated_at);
+            if elapsed >= failure_timeout * 2 {
+                return false;
+            }
+            if elapsed >= failure_timeout && state.status == MemberStatus::Alive {
+                state.status = MemberStatus::Failed;
+                changes.failed.push(address.clone());
+            }
+            true
+        });
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::gossip::membership::{Member, Membership, MembershipChanges};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn member(address: &str, heartbeat: u64) -> Member {
+        Member {
+            address: address.to_string(),
+            port: 1052,
+            heartbeat,
+            site: None,
+            public_key: None,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn merge_new_member() {
+        let now = Instant::now();
+        let mut membership = Membership::new("self".to_string(), 1052, None, None, None, TIMEOUT);
+        let changes = membership.merge(vec![member("self", 5), member("a", 1)], now);
+        assert_eq!(
+            changes,
+            MembershipChanges {
+                joined: vec![("a".to_string(), 1052)],
+                alive: vec![],
+                failed: vec![],
+            }
+        );
+        assert_eq!(membership.alive_members(), vec![("a".to_string(), 1052)]);
+    }
+    #[test]
+    fn stale_heartbeat_does_not_refresh() {
+        let now = Instant::now();
+        let mut membership = Membership::new("self".to_string(), 1052, None, None, None, TIMEOUT);
+        membership.merge(vec![member("a", 3)], now);
+        membership.merge(vec![member("a", 3)], now + TIMEOUT / 2);
+        let changes = membership.expire(now + TIMEOUT);
+        assert_eq!(changes.failed, vec!["a".to_string()]);
+        assert!(membership.alive_members().is_empty());
+        assert_eq!(membership.digest().len(), 1);
+    }
+    #[test]
+    fn newer_heartbeat_refreshes() {
+        let now = Instant::now();
+        let mut membership = Membership::new("self".to_string(), 1052, None, None, None, TIMEOUT);
+        membership.merge(vec![member("a", 3)], now);
+        let changes = membership.merge(vec![member("a", 4)], now + TIMEOUT / 2);
+        assert!(changes.joined.is_empty());
+        assert_eq!(changes.alive, vec![("a".to_string(), 1052)]);
+        assert!(membership.expire(now + TIMEOUT).failed.is_empty());
+    }
+    #[test]
+    fn failed_member_rejoins_with_newer_heartbeat() {
+        let now = Instant::now();
+        let mut membership = Membership::new("self".to_string(), 1052, None, None, None, TIMEOUT);
+        membership.merge(vec![member("a", 3)], now);
+        membership.expire(now + TIMEOUT);
+        let changes = membership.merge(vec![member("a", 3)], now + TIMEOUT);
+        assert!(changes.joined.is_empty());
+        let changes = membership.merge(vec![member("a", 4)], now + TIMEOUT);
+        assert_eq!(changes.joined, vec![("a".to_string(), 1052)]);
+    }
+    #[test]
+    fn failed_member_is_forgotten() {
+        let now = Instant::now();
+        let mut membership = Membership::new("self".to_string(), 1052, None, None, None, TIMEOUT);
+        membership.merge(vec![member("a", 3)], now);
+        membership.expire(now + TIMEOUT);
+        membership.expire(now + TIMEOUT * 2);
+        let changes = membership.merge(vec![member("a", 1)], now + TIMEOUT * 2);
+        assert_eq!(changes.joined, vec![("a".to_string(), 1052)]);
+    }
+    #[test]
+    fn site_is_gossiped() {
+        let now = Instant::now();
+        let mut membership = Membership::new("self".to_string(), 1052, None, None, None, TIMEOUT);
+        let mut a = member("a", 1);
+        a.site = Some("office".to_string());
+        membership.merge(vec![a.clone()], now);
+        assert_eq!(membership.site("a"), Some("office".to_string()));
+        assert!(membership.digest().contains(&a));
+    }
+    #[test]
+    fn beat() {
+        let mut membership = Membership::new("self".to_string(), 1052, None, None, None, TIMEOUT);
+        let initial = membership.digest()[0].heartbeat;
+        membership.beat();
+        membership.beat();
+        assert_eq!(membership.digest()[0].heartbeat, initial + 2);
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    error::{ErrorBadRequest, ErrorForbidden, ErrorServiceUnavailable},
+    post, web,
+};
+use anyhow::{Result, ensure};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+
+use crate::{
+    CLIENT,
+    activity::{Activity, Scope},
+    auth::Auth,
+    cache_policy::CachePolicy,
+    peer_registry::{Peer, PeerRegistry, authority},
+};
+
+/// Long enough for the holder to download a large package.
+const LEASE_DURATION: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaseRequest {
+    #[serde(default)]
+    pub cluster: Option<String>,
+    pub file_name: String,
+    pub holder: String,
+    pub port: u16,
+    /// Only asks who holds the lease, without taking it if nobody does.
+    #[serde(default)]
+    pub query_only: bool,
+}
+
+/// The node that fetches the file from upstream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaseHolder {
+    pub holder: String,
+    pub port: u16,
+}
+
+#[derive(Debug)]
+struct Lease {
+    holder: LeaseHolder,
+    expires_at: Instant,
+}
+
+/// Lets one node of the LAN download a file missing on every peer while the
+/// others wait for it. Each file is coordinated by the node chosen through
+/// rendezvous hashing over the known peers, which grants the first requester a
+/// lease and tells later ones who holds it. Nodes that disagree on the peer
+/// set may pick different coordinators, which only costs an extra download.
+///
+/// A node that does not serve its cache cannot hand files to others, so it
+/// neither coordinates nor takes leases, and only waits for the holder.
+#[derive(Debug)]
+pub struct Leases {
+    cluster: Option<String>,
+    own: LeaseHolder,
+    /// Whether this node coordinates and takes leases.
+    serves: bool,
+    auth: web::Data<Auth>,
+    granted: Mutex<HashMap<String, Lease>>,
+}
+impl Leases {
+    pub fn new(
+        cluster: Option<String>,
+        address: String,
+        port: u16,
+        serves: bool,
+        auth: web::Data<Auth>,
+    ) -> Self {
+        Self {
+            cluster,
+            own: LeaseHolder {
+                holder: address,
+                port,
+            },
+            serves,
+            auth,
+            granted: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn is_own(&self, holder: &LeaseHolder) -> bool {
+        *holder == self.own
+    }
+    /// The holder of the lease on the file, which goes to the requester if
+    /// nobody holds it and it asks for it.
+    fn grant(
+        &self,
+        file_name: &str,
+        requester: Option<LeaseHolder>,
+        now: Instant,
+    ) -> Option<LeaseHolder> {
+        let mut granted = self.granted.lock().unwrap();
+        granted.retain(|_, lease| now < lease.expires_at);
+        if let Some(lease) = granted.get(file_name) {
+            return Some(lease.holder.clone());
+        }
+        let holder = requester?;
+        granted.insert(
+            file_name.to_string(),
+            Lease {
+                holder: holder.clone(),
+                expires_at: now + LEASE_DURATION,
+            },
+        );
+        Some(holder)
+    }
+    /// Asks the coordinator of the file who should fetch it, claiming it for
+    /// this node if nobody has yet and this node serves its cache. Returns
+    /// `None` if nobody is fetching it.
+    pub async fn acquire(
+        &self,
+        peers: &[(String, Peer)],
+        file_name: &str,
+    ) -> Result<Option<LeaseHolder>> {
+        let own = (
+            self.own.holder.as_str(),
+            self.own.holder.as_str(),
+            self.own.port,
+        );
+        let candidates = peers
+            .iter()
+            .map(|(name, peer)| (name.as_str(), peer.host.as_str(), peer.port))
+            .chain(self.serves.then_some(own));
+        let Some((coordinator, host, port)) = coordinator(candidates, file_name) else {
+            return Ok(None);
+        };
+        if coordinator == self.own.holder {
+            return Ok(self.grant(file_name, Some(self.own.clone()), Instant::now()));
+        }
+        let body = serde_json::to_vec(&LeaseRequest {
+            cluster: self.cluster.clone(),
+            file_name: file_name.to_string(),
+            holder: self.own.holder.clone(),
+            port: self.own.port,
+            query_only: !self.serves,
+        })?;
+        let url = self.auth.sign_request(
+            "POST",
+            format!("http://{}/lease", authority(host, port)),
+            &body,
+        );
+        let response = CLIENT
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .timeout(Duration::from_secs(1))
+            .send()
+            .await?
+            .error_for_status()?;
+        let body = self.auth.read_verified(coordinator, &url, response).await?;
+        let response = serde_json::from_slice::<Option<LeaseHolder>>(&body)?;
+        ensure!(
+            response
+                .as_ref()
+                .is_none_or(|response| !response.holder.is_empty()),
+            "{} granted an empty lease",
+            url
+        );
+        Ok(response)
+    }
+}
+
+/// FNV-1a, which unlike the std hasher is guaranteed to agree between builds.
+fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash = 0xcbf29ce484222325_u64;
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Picks among `(name, host, port)` candidates by name, so that every node
+/// agrees however it reaches them.
+fn coordinator<'a>(
+    candidates: impl Iterator<Item = (&'a str, &'a str, u16)>,
+    file_name: &str,
+) -> Option<(&'a str, &'a str, u16)> {
+    candidates.max_by_key(|(name, _, _)| (fnv1a(&[name, file_name]), *name))
+}
+
+/// Whether `ip` belongs to the node named `holder`, which is looked up by name
+/// if the registry does not know it.
+async fn is_holder(peer_registry: &PeerRegistry, holder: &str, port: u16, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    if peer_registry
+        .addresses(holder)
+        .iter()
+        .any(|address| address.to_canonical() == ip)
+    {
+        return true;
+    }
+    match lookup_host((holder, port)).await {
+        Ok(mut addresses) => addresses.any(|address| address.ip().to_canonical() == ip),
+        Err(_) => false,
+    }
+}
+
+#[post("/lease")]
+async fn service_lease(
+    body: web::Bytes,
+    leases: web::Data<Leases>,
+    peer_registry: web::Data<PeerRegistry>,
+    cache_policy: web::Data<CachePolicy>,
+    activity: web::Data<Activity>,
+    http_request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    if activity.is_paused(Scope::Lan) {
+        return Err(ErrorServiceUnavailable("Paused"));
+    }
+    if !cache_policy.allows_request(&http_request) {
+        return Err(ErrorForbidden("Not allowed"));
+    }
+    let query = http_request.query_string();
+    let method = http_request.method().as_str();
+    if !leases
+        .auth
+        .authorize(method, http_request.path(), query, &body)
+    {
+        return Err(ErrorForbidden("Not signed by a trusted peer"));
+    }
+    let request = serde_json::from_slice::<LeaseRequest>(&body).map_err(ErrorBadRequest)?;
+    if request.cluster != leases.cluster {
+        return Err(ErrorForbidden("Different cluster"));
+    }
+    // A peer may only take a lease in its own name
+    if !request.query_only {
+        let Some(peer_addr) = http_request.peer_addr() else {
+            return Err(ErrorForbidden("Unknown peer"));
+        };
+        if !is_holder(
+            &peer_registry,
+            &request.holder,
+            request.port,
+            peer_addr.ip(),
+        )
+        .await
+        {
+            return Err(ErrorForbidden("Not the holder"));
+        }
+    }
+    let requester = (!request.query_only).then_some(LeaseHolder {
+        holder: request.holder,
+        port: request.port,
+    });
+    let holder = leases.grant(&request.file_name, requester, Instant::now());
+    leases.auth.signed_json(query, &holder)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use actix_web::web::Data;
+
+    use crate::{
+        auth::Auth,
+        lease::{LEASE_DURATION, LeaseHolder, Leases, coordinator, is_holder},
+        peer_registry::{Peer, PeerRegistry, PeerSource},
+    };
+
+    fn leases(serves: bool) -> Leases {
+        Leases::new(
+            None,
+            "self".to_string(),
+            1052,
+            serves,
+            Data::new(Auth::disabled()),
+        )
+    }
+    fn holder(name: &str) -> LeaseHolder {
+        LeaseHolder {
+            holder: name.to_string(),
+            port: 1052,
+        }
+    }
+
+    #[test]
+    fn first_requester_holds_lease() {
+        let now = Instant::now();
+        let leases = leases(true);
+        assert_eq!(leases.grant("foo", None, now), None);
+        assert_eq!(
+            leases.grant("foo", Some(holder("a")), now),
+            Some(holder("a"))
+        );
+        assert_eq!(
+            leases.grant("foo", Some(holder("b")), now),
+            Some(holder("a"))
+        );
+        assert_eq!(leases.grant("foo", None, now), Some(holder("a")));
+        assert_eq!(
+            leases.grant("bar", Some(holder("b")), now),
+            Some(holder("b"))
+        );
+        assert_eq!(
+            leases.grant("foo", Some(holder("b")), now + LEASE_DURATION),
+            Some(holder("b"))
+        );
+    }
+    #[test]
+    fn coordinator_is_independent_of_order() {
+        let hosts = ["a", "b", "c", "d"];
+        for file_name in ["foo.pkg.tar.zst", "bar.pkg.tar.zst", "baz.pkg.tar.zst"] {
+            let forward = coordinator(hosts.iter().map(|host| (*host, *host, 1052)), file_name);
+            let backward = coordinator(
+                hosts.iter().rev().map(|host| (*host, *host, 1052)),
+                file_name,
+            );
+            assert_eq!(forward, backward);
+        }
+    }
+    #[tokio::test]
+    async fn own_coordinator_grants_locally() -> anyhow::Result<()> {
+        let leases = leases(true);
+        let holder = leases.acquire(&[], "foo.pkg.tar.zst").await?;
+        assert!(holder.is_some_and(|holder| leases.is_own(&holder)));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn holder_must_be_the_requester() {
+        let registry = PeerRegistry::default();
+        registry.insert(
+            "a".to_string(),
+            Peer {
+                host: "a.local".to_string(),
+                port: 1052,
+                source: PeerSource::Mdns,
+                addresses: vec!["192.168.1.2".parse().unwrap()],
+                site: None,
+                tls: None,
+            },
+        );
+        let lan = "192.168.1.2".parse().unwrap();
+        assert!(is_holder(&registry, "a", 1052, lan).await);
+        assert!(is_holder(&registry, "a", 1052, "::ffff:192.168.1.2".parse().unwrap()).await);
+        assert!(!is_holder(&registry, "a", 1052, "192.168.1.3".parse().unwrap()).await);
+        assert!(is_holder(&registry, "192.168.1.2", 1052, lan).await);
+        assert!(!is_holder(&registry, "192.168.1.4", 1052, lan).await);
+    }
+    #[tokio::test]
+    async fn client_never_takes_leases() -> anyhow::Result<()> {
+        let leases = leases(false);
+        assert_eq!(leases.acquire(&[], "foo.pkg.tar.zst").await?, None);
+        Ok(())
+    }
+}
@@ -1,125 +1,487 @@
-use std::{
-    collections::HashMap,
-    net::Ipv4Addr,
-    sync::{LazyLock, Mutex},
-    time::Duration,
-};
+use std::{net::Ipv4Addr, pin::pin, rc::Rc, sync::LazyLock, time::Duration};
 
-use actix_files::Files;
+use activity::{Activity, PauseReason, Scope};
 use actix_web::{
     HttpServer,
+    dev::{ServerHandle, fn_service},
     guard::fn_guard,
+    middleware::from_fn,
     web::{Data, scope},
 };
 use anyhow::{Context, Result, ensure};
-use get_pacman_configuration::{cache_dir::get_cache_dirs, upstream_url::get_all_repository_urls};
-use neighbor_discovery::{advertise::Advertiser, browse::Browser};
+use auth::{Auth, authenticate};
+use cache_files::CacheFiles;
+use cache_policy::{CachePolicy, check_client};
+use clap::{Parser, Subcommand};
+use config::{DiscoveryConfig, LiveProxyConfig, load_config};
+use futures::StreamExt;
+use gossip::{Gossip, service_gossip};
+use lease::{Leases, service_lease};
+use log::{debug, info, warn};
+use neighbor_discovery::{
+    advertise::Advertiser,
+    browse::{Browser, HostInfo, PeerEvent},
+};
+use pacman::{Pacman, PacmanState};
+use parent::{Parents, Puller, service_pull, service_pull_health};
+use peer_registry::{Peer, PeerRegistry, PeerSource, proximity::interface_networks};
 use reqwest::Client;
-use service::service_proxy;
-use tokio::spawn;
+use sd_notify::NotifyState;
+use service::{HedgeStats, check_cluster, service_mirrorlist, service_proxy, service_proxy_status};
+use setup::{SetupArgs, TeardownArgs};
+use systemd::{Listeners, notify_state, service_health};
+use tls::{TlsEndpoint, init_client as init_tls_client, load_server_config};
+use tokio::{
+    select,
+    signal::unix::{Signal, SignalKind, signal},
+    spawn,
+    task::JoinHandle,
+    time::timeout,
+};
+use upload::{Uploads, throttle};
 
+mod activity;
+mod auth;
+mod cache_files;
+mod cache_policy;
+mod config;
 mod get_pacman_configuration;
+mod gossip;
+mod lease;
 mod neighbor_discovery;
+mod network_policy;
+mod pacman;
+mod parent;
+mod peer_registry;
+mod power_policy;
 mod service;
+mod setup;
+mod suspend;
+mod systemd;
 #[cfg(test)]
 pub mod test_utils;
+mod tls;
+mod upload;
+
+const PORT: u16 = 1052;
+/// How long responses in flight may take to finish on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the advertisement may take to be withdrawn on shutdown.
+const WITHDRAW_TIMEOUT: Duration = Duration::from_secs(3);
 
-const CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
+static CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
 
-async fn check_is_valid_upstream(url_base: &str, repository: &str) -> bool {
-    let mut db_file_url = url_base.to_string();
-    if !db_file_url.ends_with("/") {
-        db_file_url.push('/');
+/// Shares the pacman cache across hosts. Runs the daemon unless given a
+/// command.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Point pacman at cacheman.
+    Setup(SetupArgs),
+    /// Undo `cacheman setup`.
+    Teardown(TeardownArgs),
+}
+
+/// Advertises this node whenever it is not paused, until it shuts down.
+async fn advertise(
+    hostname: String,
+    config: DiscoveryConfig,
+    address: String,
+    parent: bool,
+    public_key: Option<String>,
+    tls: Option<TlsEndpoint>,
+    activity: Data<Activity>,
+) {
+    loop {
+        select! {
+            _ = activity.wait_until_paused(Scope::Serving, false) => {}
+            _ = activity.wait_for(PauseReason::ShuttingDown) => return,
+        }
+        let advertiser = Advertiser::new(
+            &hostname,
+            PORT,
+            &config,
+            &address,
+            parent,
+            public_key.as_deref(),
+            tls.as_ref(),
+        )
+        .await;
+        let advertiser = match advertiser {
+            Ok(advertiser) => advertiser,
+            Err(e) => {
+                warn!("Failed to start advertising: {e:#}");
+                return;
+            }
+        };
+        activity.set_advertised(true);
+        activity.wait_until_paused(Scope::Serving, true).await;
+        advertiser.withdraw().await;
+        activity.set_advertised(false);
+        if activity.holds(PauseReason::ShuttingDown) {
+            return;
+        }
     }
-    db_file_url.push_str(repository);
-    db_file_url.push_str(".db");
-
-    let Ok(result) = CLIENT
-        .head(&db_file_url)
-        .timeout(Duration::from_secs(3))
-        .send()
-        .await
-    else {
-        return false;
+}
+
+/// Waits for SIGTERM or SIGINT, then leaves the LAN before stopping the
+/// server: peers learn right away that this node is gone, new downloads from
+/// `/cache` are refused and responses in flight get `SHUTDOWN_TIMEOUT` to
+/// finish.
+async fn shut_down_on_signal(
+    mut terminate: Signal,
+    mut interrupt: Signal,
+    server: ServerHandle,
+    activity: Data<Activity>,
+    advertising: Option<JoinHandle<()>>,
+) {
+    select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    info!("Shutting down");
+    notify_state(&[NotifyState::Stopping]);
+    activity.set(PauseReason::ShuttingDown, true);
+    if let Some(advertising) = advertising
+        && timeout(WITHDRAW_TIMEOUT, advertising).await.is_err()
+    {
+        warn!("Failed to withdraw the advertisement in time");
+    }
+    server.stop(true).await;
+}
+
+/// The registry entry for a host found through mDNS, recording it as a
+/// parent if it is one. Hosts without a trusted key are left out.
+fn mdns_peer(host: &HostInfo, parents: &Parents, auth: &Auth) -> Option<(String, Peer)> {
+    let name = host.name();
+    if !auth.check_host(&name, host.public_key()) {
+        debug!("Ignoring {} without a trusted key", name);
+        parents.remove_discovered(&name);
+        return None;
+    }
+    if host.is_parent() {
+        parents.insert_discovered(name.clone(), host.host(), host.port);
+    } else {
+        parents.remove_discovered(&name);
+    }
+    let peer = Peer {
+        host: host.host(),
+        port: host.port,
+        source: PeerSource::Mdns,
+        addresses: host.addresses.clone(),
+        site: host.site().map(str::to_string),
+        tls: host.tls(),
     };
-    result.status().is_success()
+    Some((name, peer))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    const PORT: u16 = 1052;
-    let pacman_cache_dirs = get_cache_dirs(None).await?;
-    ensure!(
-        !pacman_cache_dirs.is_empty(),
-        "No cache directories found in pacman configuration"
-    );
-    // TODO: 複数キャッシュディレクトリに対応
-    let pacman_cache_dir = pacman_cache_dirs[0].clone();
-    // TODO: 複数リポジトリに対応
-    let mut upstream_urls = get_all_repository_urls(None)
-        .await
-        .context("Failed to get upstream URLs")?;
-
-    for (repository, urls) in upstream_urls.iter_mut() {
-        let mut handles = Vec::new();
-        for url in urls.iter() {
-            let h = spawn({
-                let url = url.clone();
-                let repository = repository.clone();
-                async move { check_is_valid_upstream(&url, &repository).await }
-            });
-            handles.push((h, url.clone()));
-        }
-        let mut new_urls = Vec::new();
-        for (handle, url) in handles {
-            let result = handle.await?;
-            if result {
-                new_urls.push(url);
+/// Keeps the peers found through mDNS in the registry whenever this node is
+/// not paused, and forgets them while it is. Browses from scratch when the
+/// machine wakes up.
+async fn browse(
+    config: DiscoveryConfig,
+    peer_registry: Data<PeerRegistry>,
+    parents: Data<Parents>,
+    auth: Data<Auth>,
+    activity: Data<Activity>,
+) {
+    loop {
+        activity.wait_until_paused(Scope::Lan, false).await;
+        let mut browser = match Browser::new(&config).await {
+            Ok(browser) => browser,
+            Err(e) => {
+                warn!("Failed to start browsing: {e:#}");
+                return;
+            }
+        };
+        let mut events = browser.events();
+        let paused = activity.wait_until_paused(Scope::Lan, true);
+        let mut paused = pin!(paused);
+        let woken = activity.woken();
+        let mut woken = pin!(woken);
+        loop {
+            let event = select! {
+                _ = &mut paused => break,
+                _ = &mut woken => break,
+                event = events.next() => event,
+            };
+            let Some(event) = event else {
+                return;
+            };
+            match event {
+                PeerEvent::Added(host) | PeerEvent::Updated(host) => {
+                    match mdns_peer(&host, &parents, &auth) {
+                        Some((host, peer)) => peer_registry.insert(host, peer),
+                        None => peer_registry.remove_from(&host.name(), PeerSource::Mdns),
+                    }
+                }
+                PeerEvent::Removed(host) => {
+                    parents.remove_discovered(&host.name());
+                    peer_registry.remove_from(&host.name(), PeerSource::Mdns);
+                }
+                PeerEvent::Resync(hosts) => {
+                    parents.clear_discovered();
+                    let peers = hosts
+                        .iter()
+                        .filter_map(|host| mdns_peer(host, &parents, &auth))
+                        .collect();
+                    peer_registry.sync_source(PeerSource::Mdns, peers);
+                }
+                PeerEvent::BrowserFailed(reason) => {
+                    debug!("mDNS peers are unavailable: {reason}");
+                }
+                PeerEvent::CacheExhausted => {}
             }
         }
+        drop(browser);
+        parents.clear_discovered();
+        peer_registry.sync_source(PeerSource::Mdns, Vec::new());
+    }
+}
 
-        *urls = new_urls;
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    match Cli::parse().command {
+        Some(Command::Setup(args)) => return setup::setup(args).await,
+        Some(Command::Teardown(args)) => return setup::teardown(args).await,
+        None => {}
     }
-    let upstream_urls = Data::new(upstream_urls);
+    let listeners = systemd::listeners()?;
+    let config = load_config(None).await?;
+    let pacman = Data::new(Pacman::new(
+        PacmanState::load(config.uses_upstream()).await?,
+    ));
+    let proxy = Data::new(LiveProxyConfig::new(config.proxy.clone()));
+    pacman::watch(pacman.clone(), config.clone(), proxy.clone())?;
 
-    Advertiser::new(
-        hostname::get()?
-            .to_str()
-            .context("Failed to get hostname")?,
-        PORT,
-    )
-    .await?;
+    let hostname = hostname::get()?
+        .to_str()
+        .context("Failed to get hostname")?
+        .to_string();
+    let auth = Data::new(Auth::new(&config.auth)?);
+    let tls = if config.tls.enabled {
+        Some(load_server_config(&config.tls, &hostname)?)
+    } else {
+        None
+    };
+    let tls_endpoint = tls.as_ref().map(|(_, endpoint)| endpoint.clone());
+    let activity = Data::new(Activity::default());
+    if config.network.has_policy() {
+        let config = config.network.clone();
+        let activity = activity.clone();
+        spawn(async move { network_policy::watch(config, &activity).await });
+    }
+    if config.power.pause_on_battery && config.node.serves_cache() {
+        let config = config.power.clone();
+        let activity = activity.clone();
+        spawn(async move { power_policy::watch(config, &activity).await });
+    }
+    // Name this node goes by among peers, which gossip also reaches it at
+    let address = config
+        .gossip
+        .address
+        .clone()
+        .unwrap_or_else(|| hostname.clone());
+    // Neither is fatal: both keep retrying until avahi-daemon is available
+    let advertising = config.node.advertises().then(|| {
+        spawn(advertise(
+            hostname,
+            config.discovery.clone(),
+            address.clone(),
+            config.parent.enabled,
+            auth.public_key(),
+            tls_endpoint.clone(),
+            activity.clone(),
+        ))
+    });
 
-    let mut peer_list = HashMap::new();
-    for peer in Browser::new().await?.get_updated_items().await? {
-        peer_list.insert(peer.hostname, PORT);
+    let parents = Data::new(Parents::new(&config.parent, address.clone(), auth.clone())?);
+    if config.node.proxies() {
+        Parents::run(parents.clone(), activity.clone());
     }
-    let peer_list = Data::new(Mutex::new(peer_list));
-
-    HttpServer::new(move || {
-        actix_web::App::new()
-            .service(
-                scope("/cache").service(
-                    Files::new("/", &pacman_cache_dir)
-                        .show_files_listing()
-                        .use_last_modified(true),
-                ),
-            )
-            .service(
+
+    let peer_registry = Data::new(PeerRegistry::new(config.discovery.site.clone()));
+    init_tls_client(&config.tls, peer_registry.clone())?;
+    if config.node.browses() {
+        spawn(browse(
+            config.discovery.clone(),
+            peer_registry.clone(),
+            parents.clone(),
+            auth.clone(),
+            activity.clone(),
+        ));
+    }
+
+    let leases = Data::new(Leases::new(
+        config.discovery.cluster.clone(),
+        address.clone(),
+        PORT,
+        config.node.serves_cache(),
+        auth.clone(),
+    ));
+    let gossip = if config.gossip.enabled {
+        let gossip = Data::new(Gossip::new(
+            &config.gossip,
+            &config.discovery,
+            address,
+            PORT,
+            config.node.serves_cache(),
+            tls_endpoint,
+            auth.clone(),
+            peer_registry.clone(),
+        )?);
+        Gossip::run(
+            gossip.clone(),
+            Duration::from_secs(config.gossip.interval_secs),
+            activity.clone(),
+        );
+        Some(gossip)
+    } else {
+        None
+    };
+    let config = Data::new(config);
+    let hedge_stats = Data::new(HedgeStats::default());
+    let cache_policy = Data::new(CachePolicy::new(&config.cache));
+    let uploads = Data::new(Uploads::new(&config.upload));
+    let io_priority = config.upload.io_priority;
+    {
+        let activity = activity.clone();
+        let uploads = uploads.clone();
+        spawn(async move { suspend::watch(&activity, &uploads).await });
+    }
+    let shutdown_activity = activity.clone();
+    systemd::report_status(peer_registry.clone(), pacman.clone(), activity.clone());
+    let puller = config.parent.enabled.then(|| Data::new(Puller::default()));
+
+    let mut server = HttpServer::new(move || {
+        let mut app = actix_web::App::new()
+            .app_data(auth.clone())
+            .app_data(config.clone())
+            .app_data(cache_policy.clone())
+            .app_data(activity.clone())
+            .app_data(leases.clone())
+            .app_data(peer_registry.clone())
+            .service(service_health)
+            .service(service_lease);
+        if let Some(gossip) = &gossip {
+            app = app.app_data(gossip.clone()).service(service_gossip);
+        }
+        if let Some(puller) = &puller {
+            app = app.service(
+                scope("/pull")
+                    .guard(fn_guard({
+                        let activity = activity.clone();
+                        move |_| !activity.is_paused(Scope::Lan)
+                    }))
+                    .wrap(from_fn(check_client))
+                    .wrap(from_fn(check_cluster))
+                    .wrap(from_fn(authenticate))
+                    .app_data(puller.clone())
+                    .app_data(cache_policy.clone())
+                    .app_data(pacman.clone())
+                    .service(service_pull_health)
+                    .service(service_pull),
+            );
+        }
+        if config.node.serves_cache() {
+            let cache_files = Rc::new(CacheFiles::new(
+                pacman.clone(),
+                cache_policy.clone(),
+                io_priority,
+            ));
+            app = app.service(
+                scope("/cache")
+                    .guard(fn_guard({
+                        let activity = activity.clone();
+                        move |_| !activity.is_paused(Scope::Lan)
+                    }))
+                    // Runs inside authenticate so that a 503 is signed as well
+                    .wrap(from_fn(throttle))
+                    .wrap(from_fn(check_client))
+                    .wrap(from_fn(check_cluster))
+                    .wrap(from_fn(authenticate))
+                    .app_data(uploads.clone())
+                    .default_service(fn_service(move |request| {
+                        let cache_files = cache_files.clone();
+                        async move { cache_files.call(request).await }
+                    })),
+            );
+        }
+        if config.node.proxies() {
+            app = app.service(
                 scope("/proxy")
-                    .guard(fn_guard(|ctx| {
-                        let Some(addr) = ctx.head().peer_addr else {
-                            return false;
-                        };
-                        addr.ip().is_loopback()
+                    .guard(fn_guard({
+                        let proxy = proxy.clone();
+                        move |ctx| {
+                            let Some(addr) = ctx.head().peer_addr else {
+                                return false;
+                            };
+                            proxy.current().allows_client(addr.ip(), interface_networks)
+                        }
                     }))
-                    .app_data(peer_list.clone())
-                    .app_data(upstream_urls.clone())
+                    .app_data(pacman.clone())
+                    .app_data(proxy.clone())
+                    .app_data(hedge_stats.clone())
+                    .app_data(parents.clone())
+                    .service(service_proxy_status)
+                    .service(service_mirrorlist)
                     .service(service_proxy),
-            )
+            );
+        }
+        app
     })
-    .bind((Ipv4Addr::UNSPECIFIED, PORT))?
-    .run()
-    .await?;
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_TIMEOUT.as_secs());
+    // Sockets passed through socket activation replace the ones bound by default
+    let Listeners {
+        http: http_listeners,
+        tls: tls_listeners,
+    } = listeners;
+    if http_listeners.is_empty() {
+        server = server.bind((Ipv4Addr::UNSPECIFIED, PORT))?;
+    }
+    for listener in http_listeners {
+        server = server.listen(listener)?;
+    }
+    let health_addr = server
+        .addrs()
+        .first()
+        .copied()
+        .context("No address to serve HTTP on")?;
+    match tls {
+        Some((server_config, endpoint)) => {
+            if tls_listeners.is_empty() {
+                server = server.bind_rustls_0_23(
+                    (Ipv4Addr::UNSPECIFIED, endpoint.port),
+                    server_config.clone(),
+                )?;
+            }
+            for listener in tls_listeners {
+                server = server.listen_rustls_0_23(listener, server_config.clone())?;
+            }
+        }
+        None => ensure!(
+            tls_listeners.is_empty(),
+            "Received sockets for TLS, but TLS is disabled"
+        ),
+    }
+    let server = server.run();
+    notify_state(&[NotifyState::Ready]);
+    systemd::run_watchdog(health_addr);
+    spawn(shut_down_on_signal(
+        signal(SignalKind::terminate())?,
+        signal(SignalKind::interrupt())?,
+        server.handle(),
+        shutdown_activity,
+        advertising,
+    ));
+    server.await?;
     Ok(())
 }
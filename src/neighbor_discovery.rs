@@ -1,14 +1,175 @@
+use std::{collections::BTreeMap, future::pending, time::Duration};
+
+use anyhow::Result;
+use futures::{
+    StreamExt,
+    stream::{self, BoxStream},
+};
+use log::debug;
+use tokio::{fs::read_dir, time::timeout};
+use zbus::{
+    Connection,
+    fdo::{DBusProxy, NameOwnerChangedStream, PropertiesProxy},
+};
+use zbus_binding::server2::Server2Proxy;
+
+use crate::config::{AddressFamily, DiscoveryConfig};
+
 pub mod advertise;
 pub mod browse;
 mod zbus_binding;
 
 const DESTINATION: &str = "org.freedesktop.Avahi";
+/// Reports interfaces and their connections coming and going.
+const NETWORK_MANAGER: &str = "org.freedesktop.NetworkManager";
+const NETWORK_MANAGER_PATH: &str = "/org/freedesktop/NetworkManager";
 
 #[cfg(not(test))]
 const SERVICE_TYPE: &str = "_cacheman._tcp";
 #[cfg(test)]
 const SERVICE_TYPE: &str = "_test-cacheman._tcp";
 
+const TXT_CLUSTER: &str = "cluster";
+const TXT_SITE: &str = "site";
+const TXT_PARENT: &str = "parent";
+const TXT_KEY: &str = "pk";
+const TXT_TLS_PORT: &str = "tls";
+const TXT_TLS_FINGERPRINT: &str = "fp";
+const TXT_ADDRESS: &str = "address";
+
+fn cluster_subtype(cluster: &str) -> String {
+    format!("_{}._sub.{}", cluster, SERVICE_TYPE)
+}
+
+/// How long to wait before trying again when Avahi fails without going away.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+const AVAHI_SERVER_RUNNING: i32 = 2;
+const AVAHI_SERVER_FAILURE: i32 = 4;
+
+const AVAHI_IF_UNSPEC: i32 = -1;
+const AVAHI_PROTO_UNSPEC: i32 = -1;
+const AVAHI_PROTO_INET: i32 = 0;
+const AVAHI_PROTO_INET6: i32 = 1;
+
+fn avahi_protocol(address_family: AddressFamily) -> i32 {
+    match address_family {
+        AddressFamily::Any => AVAHI_PROTO_UNSPEC,
+        AddressFamily::Ipv4 => AVAHI_PROTO_INET,
+        AddressFamily::Ipv6 => AVAHI_PROTO_INET6,
+    }
+}
+
+async fn avahi_owner_changes(connection: &Connection) -> Result<NameOwnerChangedStream> {
+    let dbus = DBusProxy::new(connection).await?;
+    Ok(dbus
+        .receive_name_owner_changed_with_args(&[(0, DESTINATION)])
+        .await?)
+}
+
+async fn wait_for_avahi(owner_changes: &mut NameOwnerChangedStream) {
+    let appeared = async {
+        while let Some(change) = owner_changes.next().await {
+            if change.args().is_ok_and(|args| args.new_owner().is_some()) {
+                return;
+            }
+        }
+        pending().await
+    };
+    let _ = timeout(RETRY_INTERVAL, appeared).await;
+}
+
+async fn allowed_interfaces(
+    server: &Server2Proxy<'_>,
+    config: &DiscoveryConfig,
+) -> Result<Vec<i32>> {
+    if !config.restricts_interfaces() {
+        return Ok(vec![AVAHI_IF_UNSPEC]);
+    }
+    let mut interfaces = Vec::new();
+    let mut entries = read_dir("/sys/class/net").await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !config.is_interface_allowed(&name) {
+            continue;
+        }
+        // Interfaces Avahi does not manage, such as loopback, are skipped
+        match server.get_network_interface_index_by_name(&name).await {
+            Ok(index) => interfaces.push(index),
+            Err(e) => debug!("Skipping interface {}: {}", name, e),
+        }
+    }
+    interfaces.sort();
+    Ok(interfaces)
+}
+
+/// Fires whenever NetworkManager reports a change of its devices or
+/// connections, after which the allowed interfaces may differ. Never fires
+/// when every interface is allowed anyway, or without NetworkManager.
+async fn interface_changes(
+    connection: &Connection,
+    config: &DiscoveryConfig,
+) -> BoxStream<'static, ()> {
+    if !config.restricts_interfaces() {
+        return stream::pending().boxed();
+    }
+    let changes = async {
+        let properties = PropertiesProxy::builder(connection)
+            .destination(NETWORK_MANAGER)?
+            .path(NETWORK_MANAGER_PATH)?
+            .build()
+            .await?;
+        let changes = properties
+            .receive_properties_changed_with_args(&[(0, NETWORK_MANAGER)])
+            .await?;
+        anyhow::Ok(changes.map(|_| ()).boxed())
+    };
+    changes.await.unwrap_or_else(|e| {
+        debug!("Not following interface changes: {e:#}");
+        stream::pending().boxed()
+    })
+}
+
+fn parse_txt(txt: &[Vec<u8>]) -> BTreeMap<String, String> {
+    let mut entries = BTreeMap::new();
+    for entry in txt {
+        let entry = String::from_utf8_lossy(entry);
+        let (key, value) = entry.split_once('=').unwrap_or((&entry, ""));
+        if !key.is_empty() {
+            // RFC 6763: only the first occurrence of a key counts
+            entries
+                .entry(key.to_ascii_lowercase())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::neighbor_discovery::parse_txt;
+
+    #[test]
+    fn txt() {
+        let txt = parse_txt(&[
+            b"cluster=build-farm".to_vec(),
+            b"Flag".to_vec(),
+            b"cluster=other".to_vec(),
+            b"=ignored".to_vec(),
+            b"path=/a=b".to_vec(),
+        ]);
+        assert_eq!(
+            txt,
+            [("cluster", "build-farm"), ("flag", ""), ("path", "/a=b")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        );
+    }
+}
+
 #[cfg(test)]
 mod test {
     use rand::{
@@ -1,57 +1,269 @@
-use std::mem::forget;
+use std::{
+    future::{Future, pending},
+    pin::pin,
+};
+
+use anyhow::{Result, bail};
+use futures::StreamExt;
+use log::{info, warn};
+use tokio::{select, spawn, sync::oneshot, task::JoinHandle};
+use zbus::{Connection, fdo::NameOwnerChangedStream};
 
-use anyhow::Result;
-use tokio::{spawn, sync::oneshot};
-use zbus::Connection;
+use crate::{config::DiscoveryConfig, tls::TlsEndpoint};
 
 use super::{
-    DESTINATION, SERVICE_TYPE,
+    AVAHI_SERVER_FAILURE, AVAHI_SERVER_RUNNING, DESTINATION, SERVICE_TYPE, TXT_ADDRESS,
+    TXT_CLUSTER, TXT_KEY, TXT_PARENT, TXT_SITE, TXT_TLS_FINGERPRINT, TXT_TLS_PORT,
+    allowed_interfaces, avahi_owner_changes, avahi_protocol, cluster_subtype, interface_changes,
+    wait_for_avahi,
     zbus_binding::{entry_group::EntryGroupProxy, server2::Server2Proxy},
 };
 
+const AVAHI_ENTRY_GROUP_COLLISION: i32 = 3;
+const AVAHI_ENTRY_GROUP_FAILURE: i32 = 4;
+
 pub struct Advertiser {
     sender: oneshot::Sender<()>,
+    task: JoinHandle<()>,
 }
 impl Advertiser {
-    pub async fn new(hostname: &str, port: u16) -> Result<Self> {
+    /// Keeps the service advertised in the background, registering it again
+    /// whenever avahi-daemon (re)appears. Dropping the returned value keeps the
+    /// advertisement for the lifetime of the process.
+    ///
+    /// `address` is the name the node goes by among peers, `parent` announces
+    /// it as a parent cache others can pull through, `public_key` the host key
+    /// it signs its requests with and `tls` where it serves over TLS.
+    pub async fn new(
+        hostname: &str,
+        port: u16,
+        config: &DiscoveryConfig,
+        address: &str,
+        parent: bool,
+        public_key: Option<&str>,
+        tls: Option<&TlsEndpoint>,
+    ) -> Result<Self> {
         let connection = Connection::system().await?;
-        let server = Server2Proxy::builder(&connection)
-            .destination(DESTINATION)?
-            .path("/")?
-            .build()
-            .await?;
-        let entry_group_path = server.entry_group_new().await?;
-        let entry_group = EntryGroupProxy::builder(&connection)
-            .destination(DESTINATION)?
-            .path(entry_group_path)?
-            .build()
-            .await?;
-        entry_group
-            .add_service(-1, -1, 0, hostname, SERVICE_TYPE, "", "", port, &[])
-            .await?;
-        entry_group.commit().await?;
         let (sender, receiver) = oneshot::channel();
-        spawn(async move {
-            if receiver.await.is_err() {
-                forget(connection);
-            }
-        });
-        Ok(Self { sender })
+        let mut txt = vec![format!("{}={}", TXT_ADDRESS, address)];
+        if let Some(cluster) = &config.cluster {
+            txt.push(format!("{}={}", TXT_CLUSTER, cluster));
+        }
+        if let Some(site) = &config.site {
+            txt.push(format!("{}={}", TXT_SITE, site));
+        }
+        if parent {
+            txt.push(format!("{}=1", TXT_PARENT));
+        }
+        if let Some(public_key) = public_key {
+            txt.push(format!("{}={}", TXT_KEY, public_key));
+        }
+        if let Some(tls) = tls {
+            txt.push(format!("{}={}", TXT_TLS_PORT, tls.port));
+            txt.push(format!("{}={}", TXT_TLS_FINGERPRINT, tls.fingerprint));
+        }
+        let task = spawn(advertise(
+            connection,
+            hostname.to_string(),
+            port,
+            config.clone(),
+            txt,
+            receiver,
+        ));
+        Ok(Self { sender, task })
+    }
+    /// Terminates the advertiser and returns once the service is withdrawn.
+    pub async fn withdraw(self) {
+        let _ = self.sender.send(());
+        let _ = self.task.await;
     }
-    pub fn terminate(self) {
-        self.sender.send(()).unwrap()
+}
+
+enum Stopped {
+    Terminated,
+    DaemonLost,
+}
+
+async fn advertise(
+    connection: Connection,
+    hostname: String,
+    port: u16,
+    config: DiscoveryConfig,
+    txt: Vec<String>,
+    receiver: oneshot::Receiver<()>,
+) {
+    let mut terminate = pin!(async move {
+        if receiver.await.is_err() {
+            pending::<()>().await;
+        }
+    });
+    let mut owner_changes = match avahi_owner_changes(&connection).await {
+        Ok(owner_changes) => owner_changes,
+        Err(e) => {
+            warn!("Failed to watch avahi-daemon: {e:#}");
+            return;
+        }
+    };
+    let mut name = hostname;
+    loop {
+        let result = publish(
+            &connection,
+            &mut name,
+            port,
+            &config,
+            &txt,
+            &mut owner_changes,
+            &mut terminate,
+        )
+        .await;
+        match result {
+            Ok(Stopped::Terminated) => return,
+            Ok(Stopped::DaemonLost) => info!("avahi-daemon went away, waiting for it to return"),
+            Err(e) => warn!("Failed to advertise: {e:#}"),
+        }
+        select! {
+            _ = &mut terminate => return,
+            _ = wait_for_avahi(&mut owner_changes) => {}
+        }
+    }
+}
+
+async fn publish(
+    connection: &Connection,
+    name: &mut String,
+    port: u16,
+    config: &DiscoveryConfig,
+    txt: &[String],
+    owner_changes: &mut NameOwnerChangedStream,
+    terminate: &mut (impl Future<Output = ()> + Unpin),
+) -> Result<Stopped> {
+    let server = Server2Proxy::builder(connection)
+        .destination(DESTINATION)?
+        .path("/")?
+        .build()
+        .await?;
+    let mut server_state = server.receive_state_changed().await?;
+    let entry_group_path = server.entry_group_new().await?;
+    let entry_group = EntryGroupProxy::builder(connection)
+        .destination(DESTINATION)?
+        .path(entry_group_path)?
+        .build()
+        .await?;
+    let mut entry_group_state = entry_group.receive_state_changed().await?;
+    let mut interface_changes = interface_changes(connection, config).await;
+    // Services can only be registered while the server is running; otherwise
+    // this happens once the state changes
+    let mut interfaces = Vec::new();
+    if server.get_state().await? == AVAHI_SERVER_RUNNING {
+        interfaces = add_services(&server, &entry_group, name, port, config, txt).await?;
     }
-    #[must_use = "If you want to terminate the advertiser in place, use 'Advertiser::terminate()' instead"]
-    pub fn terminate_handle(self) -> AdvertiserTerminateHandle {
-        AdvertiserTerminateHandle(Some(self))
+    loop {
+        select! {
+            _ = &mut *terminate => {
+                // The terminate future is done and must not be polled again,
+                // so a failure here cannot go through the retry loop
+                if let Err(e) = entry_group.free().await {
+                    warn!("Failed to withdraw the service: {e:#}");
+                }
+                return Ok(Stopped::Terminated);
+            }
+            _ = owner_changes.next() => return Ok(Stopped::DaemonLost),
+            state = server_state.next() => {
+                let Some(state) = state else {
+                    bail!("Lost the server state of avahi-daemon");
+                };
+                match state.args()?.state {
+                    AVAHI_SERVER_RUNNING => {
+                        if entry_group.is_empty().await? {
+                            interfaces =
+                                add_services(&server, &entry_group, name, port, config, txt)
+                                    .await?;
+                        }
+                    }
+                    AVAHI_SERVER_FAILURE => bail!("avahi-daemon failed"),
+                    // Registering or colliding while the host name changes, the
+                    // services are registered again once the server is running
+                    _ => entry_group.reset().await?,
+                }
+            }
+            state = entry_group_state.next() => {
+                let Some(state) = state else {
+                    bail!("Lost the entry group state of avahi-daemon");
+                };
+                let args = state.args()?;
+                match args.state {
+                    AVAHI_ENTRY_GROUP_COLLISION => {
+                        let alternative = server.get_alternative_service_name(name).await?;
+                        warn!("Service name {} is already taken, using {}", name, alternative);
+                        *name = alternative;
+                        entry_group.reset().await?;
+                        interfaces =
+                            add_services(&server, &entry_group, name, port, config, txt).await?;
+                    }
+                    AVAHI_ENTRY_GROUP_FAILURE => bail!("Entry group failed: {}", args.error),
+                    _ => {}
+                }
+            }
+            Some(()) = interface_changes.next() => {
+                if server.get_state().await? != AVAHI_SERVER_RUNNING
+                    || allowed_interfaces(&server, config).await? == interfaces
+                {
+                    continue;
+                }
+                info!("The allowed network interfaces changed, advertising again");
+                entry_group.reset().await?;
+                interfaces = add_services(&server, &entry_group, name, port, config, txt).await?;
+            }
+        }
     }
 }
 
-pub struct AdvertiserTerminateHandle(Option<Advertiser>);
-impl Drop for AdvertiserTerminateHandle {
-    fn drop(&mut self) {
-        self.0.take().unwrap().terminate();
+/// Returns the interfaces the services are registered on.
+async fn add_services(
+    server: &Server2Proxy<'_>,
+    entry_group: &EntryGroupProxy<'_>,
+    name: &str,
+    port: u16,
+    config: &DiscoveryConfig,
+    txt: &[String],
+) -> Result<Vec<i32>> {
+    let txt = txt.iter().map(|entry| entry.as_bytes()).collect::<Vec<_>>();
+    let protocol = avahi_protocol(config.address_family);
+    let interfaces = allowed_interfaces(server, config).await?;
+    for &interface in interfaces.iter() {
+        entry_group
+            .add_service(
+                interface,
+                protocol,
+                0,
+                name,
+                SERVICE_TYPE,
+                "",
+                "",
+                port,
+                &txt,
+            )
+            .await?;
+        if let Some(cluster) = &config.cluster {
+            entry_group
+                .add_service_subtype(
+                    interface,
+                    protocol,
+                    0,
+                    name,
+                    SERVICE_TYPE,
+                    "",
+                    &cluster_subtype(cluster),
+                )
+                .await?;
+        }
+    }
+    if interfaces.is_empty() {
+        warn!("No network interface is allowed for advertising");
+    } else {
+        entry_group.commit().await?;
     }
+    Ok(interfaces)
 }
 
 #[cfg(test)]
@@ -66,10 +278,13 @@ mod tests {
     use super::*;
 
     async fn browse_with_command(hostname: &str) -> Result<bool> {
+        browse_type_with_command(hostname, SERVICE_TYPE).await
+    }
+    async fn browse_type_with_command(hostname: &str, service_type: &str) -> Result<bool> {
         let command = Command::new("avahi-browse")
             .arg("--terminate")
             .arg("--parsable")
-            .arg(SERVICE_TYPE)
+            .arg(service_type)
             .output()
             .await?;
         ensure!(
@@ -81,7 +296,9 @@ mod tests {
 
         let is_exists = output.lines().any(|line| {
             let rows = line.split(';').collect::<Vec<_>>();
-            rows[0] == "+" && rows[3] == hostname && rows[4] == SERVICE_TYPE
+            rows[0] == "+"
+                && rows[3] == hostname
+                && (rows[4] == SERVICE_TYPE || rows[4] == service_type)
         });
 
         Ok(is_exists)
@@ -91,28 +308,83 @@ mod tests {
     async fn test_advertise() -> Result<()> {
         let hostname = generate_random_hostname(location!().as_str());
 
-        Advertiser::new(&hostname, 8080).await?;
+        Advertiser::new(
+            &hostname,
+            8080,
+            &DiscoveryConfig::default(),
+            &hostname,
+            false,
+            None,
+            None,
+        )
+        .await?;
         sleep(Duration::from_secs(1)).await;
         assert!(browse_with_command(&hostname).await?);
         yield_now().await;
         Ok(())
     }
     #[tokio::test]
+    async fn test_advertise_cluster() -> Result<()> {
+        let hostname = generate_random_hostname(location!().as_str());
+        let config = DiscoveryConfig {
+            cluster: Some("test-cluster".to_string()),
+            ..Default::default()
+        };
+
+        Advertiser::new(&hostname, 8080, &config, &hostname, false, None, None).await?;
+        sleep(Duration::from_secs(1)).await;
+        assert!(browse_with_command(&hostname).await?);
+        assert!(browse_type_with_command(&hostname, &cluster_subtype("test-cluster")).await?);
+        assert!(!browse_type_with_command(&hostname, &cluster_subtype("other-cluster")).await?);
+        Ok(())
+    }
+    #[tokio::test]
     async fn test_advertise_failure() -> Result<()> {
         let hostname0 = generate_random_hostname(location!().as_str());
         let hostname1 = generate_random_hostname(location!().as_str());
 
-        Advertiser::new(&hostname0, 8080).await?;
+        Advertiser::new(
+            &hostname0,
+            8080,
+            &DiscoveryConfig::default(),
+            &hostname0,
+            false,
+            None,
+            None,
+        )
+        .await?;
         sleep(Duration::from_secs(1)).await;
         assert!(!browse_with_command(&hostname1).await?);
         Ok(())
     }
     #[tokio::test]
-    async fn terminate_handle() -> Result<()> {
+    async fn test_advertise_collision() -> Result<()> {
         let hostname = generate_random_hostname(location!().as_str());
-        let h = Advertiser::new(&hostname, 8080).await?.terminate_handle();
+
+        Advertiser::new(
+            &hostname,
+            8080,
+            &DiscoveryConfig::default(),
+            &hostname,
+            false,
+            None,
+            None,
+        )
+        .await?;
         sleep(Duration::from_secs(1)).await;
-        drop(h);
+        Advertiser::new(
+            &hostname,
+            8081,
+            &DiscoveryConfig::default(),
+            &hostname,
+            false,
+            None,
+            None,
+        )
+        .await?;
+        sleep(Duration::from_secs(2)).await;
+        assert!(browse_with_command(&hostname).await?);
+        assert!(browse_with_command(&format!("{} #2", hostname)).await?);
         Ok(())
     }
 }
@@ -1,204 +1,594 @@
 use std::{
-    collections::HashSet,
-    convert::Infallible,
-    sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
-    },
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
 };
 
-use anyhow::{Result, ensure};
-use futures::StreamExt;
+use anyhow::{Result, anyhow};
+use futures::{
+    StreamExt,
+    stream::{BoxStream, select, unfold},
+};
+use log::{debug, info, warn};
 use tokio::{
-    spawn,
-    sync::{mpsc, oneshot},
-    task::JoinHandle,
+    select, spawn,
+    sync::{
+        broadcast::{self, error::RecvError},
+        oneshot,
+    },
 };
-use zbus::Connection;
+use zbus::{Connection, fdo::NameOwnerChangedStream};
+
+use crate::{config::DiscoveryConfig, tls::TlsEndpoint};
 
 use super::{
-    DESTINATION, SERVICE_TYPE,
-    zbus_binding::{server2::Server2Proxy, service_browser::ServiceBrowserProxy},
+    AVAHI_IF_UNSPEC, DESTINATION, SERVICE_TYPE, TXT_ADDRESS, TXT_CLUSTER, TXT_KEY, TXT_PARENT,
+    TXT_SITE, TXT_TLS_FINGERPRINT, TXT_TLS_PORT, avahi_owner_changes, avahi_protocol, parse_txt,
+    wait_for_avahi,
+    zbus_binding::{
+        server2::Server2Proxy,
+        service_browser::{ItemNew, ItemRemove, ServiceBrowserProxy},
+    },
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+const EVENT_CAPACITY: usize = 64;
+
+enum BrowserSignal {
+    New(ItemNew),
+    Remove(ItemRemove),
+    CacheExhausted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HostInfo {
+    /// Name of the service instance, which avahi may have changed to resolve a
+    /// collision.
     pub hostname: String,
+    /// Host name the service resolved to, such as `foo.local`.
+    pub host_name: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+    pub txt: BTreeMap<String, String>,
+}
+impl HostInfo {
+    /// Where to reach the host: an address it resolved to, preferring IPv4,
+    /// or its host name. IPv6 link-local addresses are left out since they
+    /// are useless without an interface.
+    pub fn host(&self) -> String {
+        self.addresses
+            .iter()
+            .find(|address| match address {
+                IpAddr::V4(_) => true,
+                IpAddr::V6(address) => !address.is_unicast_link_local(),
+            })
+            .map_or_else(|| self.host_name.clone(), IpAddr::to_string)
+    }
+    /// Name the host goes by among peers, for gossip, leases and its pinned
+    /// key: the address it advertises, or else its host name without the
+    /// `.local` domain.
+    pub fn name(&self) -> String {
+        if let Some(address) = self.txt.get(TXT_ADDRESS) {
+            return address.clone();
+        }
+        let name = self
+            .host_name
+            .strip_suffix(".local")
+            .unwrap_or(&self.host_name);
+        if name.is_empty() {
+            self.hostname.clone()
+        } else {
+            name.to_string()
+        }
+    }
+    pub fn site(&self) -> Option<&str> {
+        self.txt.get(TXT_SITE).map(String::as_str)
+    }
+    /// Ed25519 public key of the host, if it authenticates its requests.
+    pub fn public_key(&self) -> Option<&str> {
+        self.txt.get(TXT_KEY).map(String::as_str)
+    }
+    pub fn tls(&self) -> Option<TlsEndpoint> {
+        Some(TlsEndpoint {
+            port: self.txt.get(TXT_TLS_PORT)?.parse().ok()?,
+            fingerprint: self.txt.get(TXT_TLS_FINGERPRINT)?.clone(),
+        })
+    }
+    pub fn is_parent(&self) -> bool {
+        self.txt.get(TXT_PARENT).is_some_and(|value| value == "1")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Added(HostInfo),
+    Removed(HostInfo),
+    /// The port, addresses or TXT record of a known host changed.
+    Updated(HostInfo),
+    BrowserFailed(String),
+    CacheExhausted,
+    /// Every host currently known, sent instead of the events a subscriber
+    /// fell too far behind to receive.
+    Resync(Vec<HostInfo>),
+}
+/// Every host is kept with the interfaces and protocols it was seen on, and is
+/// only removed once it has disappeared from all of them.
+struct HostEntry {
+    seen_on: HashMap<(i32, i32), (Option<IpAddr>, u16)>,
+    host_name: String,
+    txt: BTreeMap<String, String>,
+}
+impl HostEntry {
+    fn info(&self, hostname: &str) -> HostInfo {
+        let mut addresses = self
+            .seen_on
+            .values()
+            .filter_map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        addresses.sort();
+        addresses.dedup();
+        let port = self
+            .seen_on
+            .values()
+            .map(|(_, port)| *port)
+            .min()
+            .unwrap_or_default();
+        HostInfo {
+            hostname: hostname.to_string(),
+            host_name: self.host_name.clone(),
+            port,
+            addresses,
+            txt: self.txt.clone(),
+        }
+    }
+}
+type Items = HashMap<String, HostEntry>;
+
+fn snapshot(items: &Mutex<Items>) -> Vec<HostInfo> {
+    items
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(hostname, entry)| entry.info(hostname))
+        .collect()
 }
+
+#[derive(Clone)]
+struct BrowserState {
+    current_items: Arc<Mutex<Items>>,
+    event_sender: broadcast::Sender<PeerEvent>,
+}
+impl BrowserState {
+    fn emit(&self, event: PeerEvent) {
+        let _ = self.event_sender.send(event);
+    }
+    fn clear(&self) {
+        let removed = self
+            .current_items
+            .lock()
+            .unwrap()
+            .drain()
+            .collect::<Vec<_>>();
+        for (hostname, entry) in removed {
+            self.emit(PeerEvent::Removed(entry.info(&hostname)));
+        }
+    }
+}
+
 pub struct Browser {
-    current_items: Arc<Mutex<HashSet<HostInfo>>>,
-    change_receiver: mpsc::Receiver<()>,
-    callback_handles: Vec<JoinHandle<Result<Infallible>>>,
+    state: BrowserState,
+    // Subscribed before the browser starts so that the first stream sees every event
+    first_event_receiver: Option<broadcast::Receiver<PeerEvent>>,
     terminate_sender: Option<oneshot::Sender<()>>,
-    is_failed: Arc<AtomicBool>,
 }
 impl Browser {
-    pub async fn new() -> Result<Self> {
+    /// Browses in the background, starting over whenever avahi-daemon
+    /// (re)appears.
+    pub async fn new(config: &DiscoveryConfig) -> Result<Self> {
         let connection = Connection::system().await?;
-        let server = Server2Proxy::builder(&connection)
-            .destination(DESTINATION)?
-            .path("/")?
-            .build()
-            .await?;
-        let browser_path = server
-            .service_browser_prepare(-1, -1, SERVICE_TYPE, "", 0)
-            .await?;
-        let browser = ServiceBrowserProxy::builder(&connection)
-            .destination(DESTINATION)?
-            .path(browser_path)?
-            .build()
-            .await?;
-
-        let current_items = Arc::new(Mutex::new(HashSet::new()));
-        let mut callback_handles = Vec::new();
-
-        let mut item_new = browser.receive_item_new().await?;
-        let h = spawn({
-            let current_items = Arc::clone(&current_items);
+        let (event_sender, first_event_receiver) = broadcast::channel(EVENT_CAPACITY);
+        let state = BrowserState {
+            current_items: Arc::new(Mutex::new(Items::new())),
+            event_sender,
+        };
+        let (terminate_sender, terminate_receiver) = oneshot::channel();
+        spawn(supervise(
+            connection,
+            config.clone(),
+            state.clone(),
+            terminate_receiver,
+        ));
+        Ok(Self {
+            state,
+            first_event_receiver: Some(first_event_receiver),
+            terminate_sender: Some(terminate_sender),
+        })
+    }
+    /// Streams changes as they happen. A subscriber that falls too far behind
+    /// gets every known host in a `Resync` instead of the dropped events.
+    pub fn events(&mut self) -> BoxStream<'static, PeerEvent> {
+        let receiver = self
+            .first_event_receiver
+            .take()
+            .unwrap_or_else(|| self.state.event_sender.subscribe());
+        let items = self.state.current_items.clone();
+        unfold(receiver, move |mut receiver| {
+            let items = items.clone();
             async move {
-                while let Some(item) = item_new.next().await {
-                    let item = item.args()?;
-                    let hostname = item.name.to_string();
-                    current_items.lock().unwrap().insert(HostInfo { hostname });
+                match receiver.recv().await {
+                    Ok(event) => Some((event, receiver)),
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("Dropped {} peer events, resynchronizing", count);
+                        // Events still queued predate the snapshot
+                        let receiver = receiver.resubscribe();
+                        Some((PeerEvent::Resync(snapshot(&items)), receiver))
+                    }
+                    Err(RecvError::Closed) => None,
                 }
-                || -> Result<Infallible> { unreachable!() }()
             }
-        });
-        callback_handles.push(h);
+        })
+        .boxed()
+    }
+}
+impl Drop for Browser {
+    fn drop(&mut self) {
+        let _ = self.terminate_sender.take().unwrap().send(());
+    }
+}
 
-        let mut item_remove = browser.receive_item_remove().await?;
-        let h = spawn({
-            let current_items = Arc::clone(&current_items);
-            async move {
-                while let Some(item) = item_remove.next().await {
-                    let item = item.args()?;
-                    let hostname = item.name.to_string();
-                    current_items.lock().unwrap().remove(&HostInfo { hostname });
+async fn supervise(
+    connection: Connection,
+    config: DiscoveryConfig,
+    state: BrowserState,
+    mut terminate: oneshot::Receiver<()>,
+) {
+    let mut owner_changes = match avahi_owner_changes(&connection).await {
+        Ok(owner_changes) => owner_changes,
+        Err(e) => {
+            warn!("Failed to watch avahi-daemon: {e:#}");
+            state.emit(PeerEvent::BrowserFailed(format!("{e:#}")));
+            return;
+        }
+    };
+    loop {
+        // Returning from `browse` means the browser stopped working
+        let result = select! {
+            _ = &mut terminate => return,
+            result = browse(&connection, &config, &state, &mut owner_changes) => result,
+        };
+        let reason = match result {
+            Ok(()) => {
+                info!("avahi-daemon went away, waiting for it to return");
+                "avahi-daemon went away".to_string()
+            }
+            Err(e) => {
+                warn!("Browsing failed: {e:#}");
+                format!("{e:#}")
+            }
+        };
+        state.clear();
+        state.emit(PeerEvent::BrowserFailed(reason));
+        select! {
+            _ = &mut terminate => return,
+            _ = wait_for_avahi(&mut owner_changes) => {}
+        }
+    }
+}
+
+async fn browse(
+    connection: &Connection,
+    config: &DiscoveryConfig,
+    state: &BrowserState,
+    owner_changes: &mut NameOwnerChangedStream,
+) -> Result<()> {
+    let server = Server2Proxy::builder(connection)
+        .destination(DESTINATION)?
+        .path("/")?
+        .build()
+        .await?;
+    let browser_path = server
+        .service_browser_prepare(
+            AVAHI_IF_UNSPEC,
+            avahi_protocol(config.address_family),
+            SERVICE_TYPE,
+            "",
+            0,
+        )
+        .await?;
+    let browser = ServiceBrowserProxy::builder(connection)
+        .destination(DESTINATION)?
+        .path(browser_path)?
+        .build()
+        .await?;
+
+    let item_new = browser.receive_item_new().await?.map(BrowserSignal::New);
+    let item_remove = browser
+        .receive_item_remove()
+        .await?
+        .map(BrowserSignal::Remove);
+    let cache_exhausted = browser
+        .receive_cache_exhausted()
+        .await?
+        .map(|_| BrowserSignal::CacheExhausted);
+    let mut signals = select(select(item_new, item_remove), cache_exhausted);
+    let mut on_failure = browser.receive_failure().await?;
+
+    state.clear();
+    browser.start().await?;
+
+    let result = loop {
+        select! {
+            _ = owner_changes.next() => return Ok(()),
+            failure = on_failure.next() => {
+                let error = match &failure {
+                    Some(failure) => failure.args()?.error.to_string(),
+                    None => "signal stream closed".to_string(),
+                };
+                break Err(anyhow!("Avahi browser failed: {}", error));
+            }
+            signal = signals.next() => {
+                let Some(signal) = signal else {
+                    break Err(anyhow!("Avahi browser signal stream closed"));
+                };
+                if let Err(e) = handle_signal(&server, config, state, signal).await {
+                    break Err(e);
                 }
-                || -> Result<Infallible> { unreachable!() }()
             }
-        });
-        callback_handles.push(h);
+        }
+    };
+    let _ = browser.free().await;
+    result
+}
 
-        let mut all_for_now = browser.receive_all_for_now().await?;
-        let (change_sender, change_receiver) = mpsc::channel(1);
-        let h = spawn({
-            async move {
-                while let Some(_) = all_for_now.next().await {
-                    let _ = change_sender.try_send(());
+async fn handle_signal(
+    server: &Server2Proxy<'_>,
+    config: &DiscoveryConfig,
+    state: &BrowserState,
+    signal: BrowserSignal,
+) -> Result<()> {
+    match signal {
+        BrowserSignal::New(item) => {
+            let item = item.args()?;
+            if config.restricts_interfaces() {
+                let name = server
+                    .get_network_interface_name_by_index(item.interface)
+                    .await;
+                if !name.is_ok_and(|name| config.is_interface_allowed(&name)) {
+                    return Ok(());
                 }
-                || -> Result<Infallible> { unreachable!() }()
             }
-        });
-        callback_handles.push(h);
-        let is_failed = Arc::new(AtomicBool::new(false));
-        let mut on_failure = browser.receive_failure().await?;
-        let h = spawn({
-            let is_failed = Arc::clone(&is_failed);
-            async move {
-                while let Some(_) = on_failure.next().await {
-                    is_failed.store(true, Ordering::SeqCst);
+            let resolved = server
+                .resolve_service(
+                    item.interface,
+                    item.protocol,
+                    item.name,
+                    item.type_,
+                    item.domain,
+                    -1,
+                    0,
+                )
+                .await;
+            let (host_name, address, port, txt) = match resolved {
+                Ok((_, _, _, _, _, host_name, _, address, port, txt, _)) => (
+                    host_name,
+                    address.parse::<IpAddr>().ok(),
+                    port,
+                    parse_txt(&txt),
+                ),
+                Err(e) => {
+                    debug!("Failed to resolve {}: {}", item.name, e);
+                    return Ok(());
                 }
-                || -> Result<Infallible> { unreachable!() }()
+            };
+            if txt.get(TXT_CLUSTER) != config.cluster.as_ref() {
+                return Ok(());
+            }
+            let hostname = item.name;
+            let mut current_items = state.current_items.lock().unwrap();
+            let event = match current_items.get_mut(hostname) {
+                Some(entry) => {
+                    let before = entry.info(hostname);
+                    entry
+                        .seen_on
+                        .insert((item.interface, item.protocol), (address, port));
+                    entry.host_name = host_name;
+                    entry.txt = txt;
+                    let after = entry.info(hostname);
+                    (before != after).then_some(PeerEvent::Updated(after))
+                }
+                None => {
+                    let entry = HostEntry {
+                        seen_on: HashMap::from([(
+                            (item.interface, item.protocol),
+                            (address, port),
+                        )]),
+                        host_name,
+                        txt,
+                    };
+                    let info = entry.info(hostname);
+                    current_items.insert(hostname.to_string(), entry);
+                    Some(PeerEvent::Added(info))
+                }
+            };
+            drop(current_items);
+            if let Some(event) = event {
+                state.emit(event);
+            }
+        }
+        BrowserSignal::Remove(item) => {
+            let item = item.args()?;
+            let hostname = item.name;
+            let mut current_items = state.current_items.lock().unwrap();
+            let Some(entry) = current_items.get_mut(hostname) else {
+                return Ok(());
+            };
+            let before = entry.info(hostname);
+            if entry
+                .seen_on
+                .remove(&(item.interface, item.protocol))
+                .is_none()
+            {
+                return Ok(());
+            }
+            let event = if entry.seen_on.is_empty() {
+                current_items.remove(hostname);
+                Some(PeerEvent::Removed(before))
+            } else {
+                let after = entry.info(hostname);
+                (before != after).then_some(PeerEvent::Updated(after))
+            };
+            drop(current_items);
+            if let Some(event) = event {
+                state.emit(event);
             }
-        });
-        callback_handles.push(h);
-
-        browser.start().await?;
-
-        let (terminate_sender, terminate_receiver) = oneshot::channel();
-        spawn(async move {
-            terminate_receiver.await.unwrap();
-            browser.free().await.unwrap();
-        });
-
-        Ok(Self {
-            current_items,
-            change_receiver,
-            callback_handles,
-            terminate_sender: Some(terminate_sender),
-            is_failed,
-        })
-    }
-    pub fn get_current_items(&self) -> Result<Vec<HostInfo>> {
-        ensure!(!self.is_failed.load(Ordering::SeqCst), "Browser is failed");
-        let current_items = self.current_items.lock().unwrap();
-        Ok(current_items.iter().cloned().collect())
-    }
-    pub async fn get_updated_items(&mut self) -> Result<Vec<HostInfo>> {
-        ensure!(!self.is_failed.load(Ordering::SeqCst), "Browser is failed");
-        self.change_receiver.recv().await;
-        self.get_current_items()
-    }
-}
-impl Drop for Browser {
-    fn drop(&mut self) {
-        for handle in self.callback_handles.drain(..) {
-            handle.abort();
         }
-        self.terminate_sender.take().unwrap().send(()).unwrap();
+        BrowserSignal::CacheExhausted => state.emit(PeerEvent::CacheExhausted),
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{
+        collections::{BTreeMap, HashMap},
+        future::ready,
+        time::Duration,
+    };
 
     use anyhow::Result;
-
+    use futures::StreamExt;
     use tokio::{
         process::{Child, Command},
-        time::sleep,
+        time::{sleep, timeout},
     };
 
     use crate::{
+        config::DiscoveryConfig,
         location,
         neighbor_discovery::{
             SERVICE_TYPE,
-            browse::{Browser, HostInfo},
+            browse::{Browser, HostInfo, PeerEvent},
             test::generate_random_hostname,
         },
     };
 
-    async fn advertise_with_command(hostname: &str, port: u16) -> Result<Child> {
+    async fn advertise_with_command(hostname: &str, port: u16, txt: &[&str]) -> Result<Child> {
         let cmd = Command::new("avahi-publish")
             .arg("-s")
             .arg(hostname)
             .arg(SERVICE_TYPE)
             .arg(port.to_string())
+            .args(txt)
             .kill_on_drop(true)
             .spawn()?;
         anyhow::Ok(cmd)
     }
+    fn find<'a>(items: &'a [HostInfo], hostname: &str) -> Option<&'a HostInfo> {
+        items.iter().find(|host| host.hostname == hostname)
+    }
+    /// The hosts known after following the events for a while.
+    async fn browse_for(config: &DiscoveryConfig, duration: Duration) -> Result<Vec<HostInfo>> {
+        let mut browser = Browser::new(config).await?;
+        let mut events = browser.events();
+        let mut hosts = HashMap::new();
+        let _ = timeout(duration, async {
+            while let Some(event) = events.next().await {
+                match event {
+                    PeerEvent::Added(host) | PeerEvent::Updated(host) => {
+                        hosts.insert(host.hostname.clone(), host);
+                    }
+                    PeerEvent::Removed(host) => {
+                        hosts.remove(&host.hostname);
+                    }
+                    PeerEvent::Resync(all) => {
+                        hosts = all
+                            .into_iter()
+                            .map(|host| (host.hostname.clone(), host))
+                            .collect();
+                    }
+                    PeerEvent::BrowserFailed(_) | PeerEvent::CacheExhausted => {}
+                }
+            }
+        })
+        .await;
+        Ok(hosts.into_values().collect())
+    }
+    #[test]
+    fn name_and_host() {
+        let mut host = HostInfo {
+            hostname: "foo #2".to_string(),
+            host_name: "foo.local".to_string(),
+            port: 1052,
+            addresses: vec!["fe80::1".parse().unwrap(), "fd00::1".parse().unwrap()],
+            txt: BTreeMap::new(),
+        };
+        assert_eq!(host.name(), "foo");
+        assert_eq!(host.host(), "fd00::1");
+        host.addresses.push("192.168.1.2".parse().unwrap());
+        host.addresses.sort();
+        assert_eq!(host.host(), "192.168.1.2");
+        host.addresses.clear();
+        assert_eq!(host.host(), "foo.local");
+        host.txt
+            .insert("address".to_string(), "gateway.example.com".to_string());
+        assert_eq!(host.name(), "gateway.example.com");
+    }
     #[tokio::test]
     async fn test_browse() -> Result<()> {
         let hostname = generate_random_hostname(location!());
-        let mut _c = advertise_with_command(&hostname, 8080).await?;
+        let mut _c = advertise_with_command(&hostname, 8080, &["site=test-site"]).await?;
         sleep(Duration::from_secs(1)).await;
-        assert!(
-            Browser::new()
-                .await?
-                .get_updated_items()
-                .await?
-                .contains(&HostInfo { hostname })
-        );
+        let items = browse_for(&DiscoveryConfig::default(), Duration::from_secs(2)).await?;
+        let host = find(&items, &hostname).expect("advertised host is found");
+        assert_eq!(host.port, 8080);
+        assert!(!host.addresses.is_empty());
+        assert_eq!(host.site(), Some("test-site"));
         Ok(())
     }
     #[tokio::test]
     async fn test_browse_failure() -> Result<()> {
         let hostname0 = generate_random_hostname(location!());
         let hostname1 = generate_random_hostname(location!());
-        let mut _c = advertise_with_command(&hostname0, 8080).await?;
+        let mut _c = advertise_with_command(&hostname0, 8080, &[]).await?;
         sleep(Duration::from_secs(1)).await;
-        assert!(
-            !Browser::new()
-                .await?
-                .get_updated_items()
-                .await?
-                .contains(&HostInfo {
-                    hostname: hostname1
-                })
-        );
+        let items = browse_for(&DiscoveryConfig::default(), Duration::from_secs(2)).await?;
+        assert!(find(&items, &hostname1).is_none());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_browse_cluster() -> Result<()> {
+        let hostname0 = generate_random_hostname(location!());
+        let hostname1 = generate_random_hostname(location!());
+        let hostname2 = generate_random_hostname(location!());
+        let mut _c0 = advertise_with_command(&hostname0, 8080, &["cluster=test-a"]).await?;
+        let mut _c1 = advertise_with_command(&hostname1, 8080, &["cluster=test-b"]).await?;
+        let mut _c2 = advertise_with_command(&hostname2, 8080, &[]).await?;
+        sleep(Duration::from_secs(1)).await;
+        let config = DiscoveryConfig {
+            cluster: Some("test-a".to_string()),
+            ..Default::default()
+        };
+        let items = browse_for(&config, Duration::from_secs(2)).await?;
+        assert!(find(&items, &hostname0).is_some());
+        assert!(find(&items, &hostname1).is_none());
+        assert!(find(&items, &hostname2).is_none());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_events() -> Result<()> {
+        let hostname = generate_random_hostname(location!());
+        let mut browser = Browser::new(&DiscoveryConfig::default()).await?;
+        let mut events = browser.events().filter(|event| {
+            ready(matches!(
+                event,
+                PeerEvent::Added(host) | PeerEvent::Removed(host) if host.hostname == hostname
+            ))
+        });
+        let child = advertise_with_command(&hostname, 8080, &[]).await?;
+        let event = timeout(Duration::from_secs(5), events.next()).await?;
+        assert!(matches!(event, Some(PeerEvent::Added(host)) if host.port == 8080));
+        drop(child);
+        let event = timeout(Duration::from_secs(5), events.next()).await?;
+        assert!(matches!(event, Some(PeerEvent::Removed(host)) if host.port == 8080));
         Ok(())
     }
 }
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use futures::{StreamExt, stream::select};
+use log::{debug, warn};
+use zbus::{
+    Connection,
+    fdo::{DBusProxy, PropertiesProxy},
+    proxy::CacheProperties,
+};
+use zbus_binding::{ActiveConnectionProxy, NetworkManagerProxy};
+
+use crate::{
+    activity::{Activity, PauseReason},
+    config::NetworkConfig,
+};
+
+mod zbus_binding;
+
+const DESTINATION: &str = "org.freedesktop.NetworkManager";
+const PATH: &str = "/org/freedesktop/NetworkManager";
+
+// Values of NMMetered meaning the connection is metered
+const NM_METERED_YES: u32 = 1;
+const NM_METERED_GUESS_YES: u32 = 3;
+
+struct NetworkState {
+    metered: bool,
+    /// IDs of the active connections.
+    connections: Vec<String>,
+}
+
+async fn read_state(connection: &Connection) -> Result<NetworkState> {
+    let network_manager = NetworkManagerProxy::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let metered = matches!(
+        network_manager.metered().await?,
+        NM_METERED_YES | NM_METERED_GUESS_YES
+    );
+    let mut connections = Vec::new();
+    for path in network_manager.active_connections().await? {
+        let active_connection = ActiveConnectionProxy::builder(connection)
+            .path(path)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        // The connection may have gone down in the meantime
+        if let Ok(id) = active_connection.id().await {
+            connections.push(id);
+        }
+    }
+    Ok(NetworkState {
+        metered,
+        connections,
+    })
+}
+
+/// Pauses the node according to the policy whenever the connections
+/// NetworkManager reports change. Without NetworkManager, nothing is paused.
+pub async fn watch(config: NetworkConfig, activity: &Activity) {
+    let changes = async {
+        let connection = Connection::system().await?;
+        let dbus = DBusProxy::new(&connection).await?;
+        let owner_changes = dbus
+            .receive_name_owner_changed_with_args(&[(0, DESTINATION)])
+            .await?
+            .map(|_| ());
+        let properties = PropertiesProxy::builder(&connection)
+            .destination(DESTINATION)?
+            .path(PATH)?
+            .build()
+            .await?;
+        let property_changes = properties
+            .receive_properties_changed_with_args(&[(0, DESTINATION)])
+            .await?
+            .map(|_| ());
+        anyhow::Ok((connection, select(owner_changes, property_changes)))
+    };
+    let (connection, mut changes) = match changes.await {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn!("Failed to watch NetworkManager: {e:#}");
+            return;
+        }
+    };
+    loop {
+        match read_state(&connection).await {
+            Ok(state) => {
+                activity.set(
+                    PauseReason::MeteredConnection,
+                    config.pause_when_metered && state.metered,
+                );
+                activity.set(
+                    PauseReason::UntrustedConnection,
+                    config.is_untrusted(&state.connections),
+                );
+            }
+            Err(e) => {
+                debug!("NetworkManager is unavailable: {e:#}");
+                activity.set(PauseReason::MeteredConnection, false);
+                activity.set(PauseReason::UntrustedConnection, false);
+            }
+        }
+        if changes.next().await.is_none() {
+            return;
+        }
+    }
+}
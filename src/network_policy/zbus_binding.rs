@@ -0,0 +1,30 @@
+//! # D-Bus interface proxies for the parts of NetworkManager cacheman uses
+//!
+//! Adapted from the introspection data of `org.freedesktop.NetworkManager` and
+//! `org.freedesktop.NetworkManager.Connection.Active`.
+use zbus::proxy;
+
+#[proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+pub trait NetworkManager {
+    /// ActiveConnections property
+    #[zbus(property)]
+    fn active_connections(&self) -> zbus::Result<Vec<zbus::zvariant::OwnedObjectPath>>;
+
+    /// Metered property
+    #[zbus(property)]
+    fn metered(&self) -> zbus::Result<u32>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.NetworkManager.Connection.Active",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+pub trait ActiveConnection {
+    /// Id property
+    #[zbus(property)]
+    fn id(&self) -> zbus::Result<String>;
+}
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use actix_web::web;
+use anyhow::{Context, Result, ensure};
+use futures::{
+    StreamExt,
+    stream::{self, BoxStream},
+};
+use inotify::{Inotify, WatchMask};
+use log::{info, warn};
+use tokio::{
+    select,
+    signal::unix::{SignalKind, signal},
+    spawn,
+    time::timeout,
+};
+
+use crate::{
+    CLIENT,
+    config::{Config, LiveProxyConfig, load_config},
+    get_pacman_configuration::pacman_conf,
+};
+
+/// Files whose changes trigger a reload: the main configuration, and the
+/// mirrorlists and other includes next to it.
+const PACMAN_CONF: &str = "/etc/pacman.conf";
+const PACMAN_D: &str = "/etc/pacman.d";
+/// Editors and package upgrades touch several files in a row; they are
+/// reloaded together once quiet for this long.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+async fn check_is_valid_upstream(url_base: &str, repository: &str) -> bool {
+    let mut db_file_url = url_base.to_string();
+    if !db_file_url.ends_with("/") {
+        db_file_url.push('/');
+    }
+    db_file_url.push_str(repository);
+    db_file_url.push_str(".db");
+
+    let Ok(result) = CLIENT
+        .head(&db_file_url)
+        .timeout(Duration::from_secs(3))
+        .send()
+        .await
+    else {
+        return false;
+    };
+    result.status().is_success()
+}
+
+/// What cacheman takes from the pacman configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacmanState {
+    /// Looked through in order; pulled packages are saved in the first one.
+    pub cache_dirs: Vec<PathBuf>,
+    /// Mirrors of each repository that answered when the state was loaded.
+    pub upstream_urls: HashMap<String, Vec<String>>,
+}
+impl PacmanState {
+    /// Without upstream, mirrors are neither read nor checked.
+    pub async fn load(upstream: bool) -> Result<Self> {
+        let pacman_conf = pacman_conf(None)
+            .await
+            .context("Failed to read the pacman configuration")?;
+        let cache_dirs = pacman_conf.cache_dirs;
+        ensure!(
+            !cache_dirs.is_empty(),
+            "No cache directories found in pacman configuration"
+        );
+        if !upstream {
+            return Ok(Self {
+                cache_dirs,
+                upstream_urls: HashMap::new(),
+            });
+        }
+        let mut upstream_urls = pacman_conf.repositories;
+
+        for (repository, urls) in upstream_urls.iter_mut() {
+            let mut handles = Vec::new();
+            for url in urls.iter() {
+                let h = spawn({
+                    let url = url.clone();
+                    let repository = repository.clone();
+                    async move { check_is_valid_upstream(&url, &repository).await }
+                });
+                handles.push((h, url.clone()));
+            }
+            let mut new_urls = Vec::new();
+            for (handle, url) in handles {
+                let result = handle.await?;
+                if result {
+                    new_urls.push(url);
+                }
+            }
+
+            *urls = new_urls;
+        }
+        Ok(Self {
+            cache_dirs,
+            upstream_urls,
+        })
+    }
+}
+
+/// The current pacman state, replaced as a whole once a new one is loaded so
+/// that requests never see a half-built one.
+#[derive(Debug)]
+pub struct Pacman {
+    state: RwLock<Arc<PacmanState>>,
+}
+impl Pacman {
+    pub fn new(state: PacmanState) -> Self {
+        Self {
+            state: RwLock::new(Arc::new(state)),
+        }
+    }
+    pub fn current(&self) -> Arc<PacmanState> {
+        self.state.read().unwrap().clone()
+    }
+    /// Returns whether the state changed.
+    pub fn replace(&self, state: PacmanState) -> bool {
+        let mut current = self.state.write().unwrap();
+        if **current == state {
+            return false;
+        }
+        *current = Arc::new(state);
+        true
+    }
+    /// Loads the pacman configuration again. The previous state stays in use
+    /// until the new one is complete, or if it fails to load.
+    pub async fn reload(&self, upstream: bool) -> Result<()> {
+        let state = PacmanState::load(upstream).await?;
+        let repositories = state.upstream_urls.len();
+        if self.replace(state) {
+            info!("Reloaded the pacman configuration: {repositories} repositories");
+        }
+        Ok(())
+    }
+}
+
+fn watch_files() -> Result<BoxStream<'static, ()>> {
+    let inotify = Inotify::init()?;
+    let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::DELETE;
+    let pacman_conf = Path::new(PACMAN_CONF);
+    let etc = inotify
+        .watches()
+        .add(pacman_conf.parent().unwrap_or(Path::new("/")), mask)?;
+    if let Err(e) = inotify.watches().add(PACMAN_D, mask) {
+        warn!("Failed to watch {PACMAN_D}: {e}");
+    }
+    let file_name = pacman_conf.file_name().map(OsStr::to_os_string);
+    let events = inotify.into_event_stream([0; 4096])?;
+    Ok(events
+        .filter_map(move |event| {
+            // Only pacman.conf matters among the files of its directory
+            let relevant = event.is_ok_and(|event| {
+                event.wd != etc || event.name.as_deref() == file_name.as_deref()
+            });
+            async move { relevant.then_some(()) }
+        })
+        .boxed())
+}
+
+/// Reloads the pacman configuration on SIGHUP and whenever its files change.
+/// SIGHUP also applies the `[proxy]` section of the cacheman configuration;
+/// the other sections are only read at startup, so it only warns when they
+/// changed.
+pub fn watch(
+    pacman: web::Data<Pacman>,
+    mut config: Config,
+    proxy: web::Data<LiveProxyConfig>,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut changes = watch_files().unwrap_or_else(|e| {
+        warn!("Failed to watch the pacman configuration: {e:#}");
+        stream::pending().boxed()
+    });
+    spawn(async move {
+        loop {
+            select! {
+                _ = hangup.recv() => {
+                    match load_config(None).await {
+                        Ok(new_config) => {
+                            if proxy.replace(new_config.proxy.clone()) {
+                                info!("Applied the new proxy configuration");
+                                config.proxy = new_config.proxy.clone();
+                            }
+                            if new_config != config {
+                                warn!(
+                                    "The cacheman configuration changed outside of [proxy], \
+                                    restart to apply it"
+                                );
+                            }
+                        }
+                        Err(e) => warn!("Invalid cacheman configuration: {e:#}"),
+                    }
+                }
+                Some(()) = changes.next() => {
+                    while let Ok(Some(())) = timeout(SETTLE_TIME, changes.next()).await {}
+                }
+            }
+            if let Err(e) = pacman.reload(config.uses_upstream()).await {
+                warn!("Failed to reload the pacman configuration, keeping the previous one: {e:#}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::pacman::{Pacman, PacmanState};
+
+    #[test]
+    fn replace_swaps_whole_state() {
+        let pacman = Pacman::new(PacmanState {
+            cache_dirs: vec!["/var/cache/pacman/pkg".into()],
+            upstream_urls: HashMap::from([("core".to_string(), vec!["https://a".to_string()])]),
+        });
+        let before = pacman.current();
+        assert!(!pacman.replace((*before).clone()));
+        let state = PacmanState {
+            cache_dirs: vec!["/var/cache/pacman/pkg".into()],
+            upstream_urls: HashMap::from([
+                ("core".to_string(), vec!["https://a".to_string()]),
+                ("multilib".to_string(), vec!["https://a".to_string()]),
+            ]),
+        };
+        assert!(pacman.replace(state.clone()));
+        assert_eq!(*pacman.current(), state);
+        // Requests holding the previous state keep a consistent view
+        assert_eq!(before.upstream_urls.len(), 1);
+    }
+}
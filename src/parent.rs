@@ -0,0 +1,336 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+
+use actix_files::NamedFile;
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    error::{ErrorBadGateway, ErrorBadRequest, ErrorInternalServerError, ErrorNotFound},
+    get,
+    http::StatusCode,
+    web::{self, Bytes, Redirect},
+};
+use anyhow::{Context, Result};
+use futures::stream::unfold;
+use log::{debug, info, warn};
+use reqwest::header::CONTENT_LENGTH;
+use tokio::{
+    fs::{File, remove_file, rename},
+    io::AsyncWriteExt,
+    spawn,
+    sync::mpsc,
+    time::interval,
+};
+
+use crate::{
+    CLIENT,
+    activity::{Activity, Scope},
+    auth::Auth,
+    cache_policy::CachePolicy,
+    config::ParentConfig,
+    gossip::parse_seed,
+    pacman::Pacman,
+    peer_registry::authority,
+    service::upstream_url,
+};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Parent caches this node can use as its upstream: the configured one first,
+/// then the ones advertising themselves over mDNS. Only a parent that passed
+/// the last health check is used.
+#[derive(Debug)]
+pub struct Parents {
+    own_address: String,
+    configured: Option<(String, u16)>,
+    discover: bool,
+    /// Host and port of each parent found through mDNS, by name.
+    discovered: Mutex<BTreeMap<String, (String, u16)>>,
+    healthy: Mutex<Option<(String, u16)>>,
+    auth: web::Data<Auth>,
+}
+impl Parents {
+    pub fn new(config: &ParentConfig, own_address: String, auth: web::Data<Auth>) -> Result<Self> {
+        let configured = config.address.as_deref().map(parse_seed).transpose()?;
+        Ok(Self {
+            own_address,
+            configured,
+            discover: config.discover,
+            discovered: Mutex::new(BTreeMap::new()),
+            healthy: Mutex::new(None),
+            auth,
+        })
+    }
+    pub fn insert_discovered(&self, name: String, host: String, port: u16) {
+        if self.discover {
+            self.discovered.lock().unwrap().insert(name, (host, port));
+        }
+    }
+    pub fn remove_discovered(&self, name: &str) {
+        self.discovered.lock().unwrap().remove(name);
+    }
+    pub fn clear_discovered(&self) {
+        self.discovered.lock().unwrap().clear();
+    }
+    /// The parents to try as `(name, host, port)`, where the name is what
+    /// their key is known by.
+    fn candidates(&self) -> Vec<(String, String, u16)> {
+        let discovered = self.discovered.lock().unwrap();
+        self.configured
+            .iter()
+            .map(|(host, port)| (host.clone(), host.clone(), *port))
+            .chain(
+                discovered
+                    .iter()
+                    .map(|(name, (host, port))| (name.clone(), host.clone(), *port)),
+            )
+            .filter(|(name, _, _)| *name != self.own_address)
+            .collect()
+    }
+    /// URL to pull the file through the healthy parent, if there is one.
+    pub fn pull_url(&self, arch: &str, repo: &str, file_name: &str) -> Option<String> {
+        let healthy = self.healthy.lock().unwrap();
+        let (host, port) = healthy.as_ref()?;
+        Some(self.auth.sign_url(format!(
+            "http://{}/pull/{}/{}/{}",
+            authority(host, *port),
+            arch,
+            repo,
+            file_name
+        )))
+    }
+    async fn check_health(&self) {
+        let mut healthy = None;
+        for (name, host, port) in self.candidates() {
+            let url = self
+                .auth
+                .sign_url(format!("http://{}/pull/health", authority(&host, port)));
+            let result = async {
+                let response = CLIENT
+                    .get(&url)
+                    .timeout(Duration::from_secs(2))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                self.auth.read_verified(&name, &url, response).await
+            }
+            .await;
+            match result {
+                Ok(_) => {
+                    healthy = Some((host, port));
+                    break;
+                }
+                Err(e) => debug!("Parent {}:{} is unavailable: {:#}", host, port, e),
+            }
+        }
+        let mut current = self.healthy.lock().unwrap();
+        if *current != healthy {
+            match &healthy {
+                Some((host, port)) => info!("Using parent cache {}:{}", host, port),
+                None => info!("No parent cache is available"),
+            }
+            *current = healthy;
+        }
+    }
+    /// Checks the parents periodically, except while the node is paused.
+    pub fn run(parents: web::Data<Self>, activity: web::Data<Activity>) {
+        spawn(async move {
+            let mut interval = interval(HEALTH_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if activity.is_paused(Scope::Lan) {
+                    activity.wait_until_paused(Scope::Lan, false).await;
+                    interval.reset();
+                }
+                parents.check_health().await;
+            }
+        });
+    }
+}
+
+/// Downloads files missing from the cache directory from upstream, streaming
+/// them to the requester while saving them.
+#[derive(Debug, Default)]
+pub struct Puller {
+    in_progress: Mutex<HashSet<String>>,
+}
+impl Puller {
+    /// Only one download of a file is saved at a time; concurrent requests are
+    /// streamed from upstream without saving.
+    fn claim(&self, file_name: &str) -> bool {
+        self.in_progress
+            .lock()
+            .unwrap()
+            .insert(file_name.to_string())
+    }
+    fn release(&self, file_name: &str) {
+        self.in_progress.lock().unwrap().remove(file_name);
+    }
+}
+
+async fn save(
+    puller: web::Data<Puller>,
+    cache_dir: PathBuf,
+    file_name: String,
+    mut response: reqwest::Response,
+    sender: mpsc::Sender<Bytes>,
+) {
+    let part_path = cache_dir.join(format!(".{}.part", file_name));
+    let result = async {
+        let mut part = File::create(&part_path).await?;
+        while let Some(chunk) = response.chunk().await? {
+            part.write_all(&chunk).await?;
+            // The download is finished and saved even if the requester goes away
+            let _ = sender.send(chunk).await;
+        }
+        part.sync_all().await?;
+        rename(&part_path, cache_dir.join(&file_name)).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to pull {}: {:#}", file_name, e);
+        let _ = remove_file(&part_path).await;
+    }
+    puller.release(&file_name);
+}
+
+async fn forward(mut response: reqwest::Response, sender: mpsc::Sender<Bytes>) {
+    while let Ok(Some(chunk)) = response.chunk().await {
+        if sender.send(chunk).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[get("/health")]
+async fn service_pull_health() -> impl Responder {
+    "ok"
+}
+
+#[get("/{arch}/{repo}/{file_name}")]
+async fn service_pull(
+    request: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    pacman: web::Data<Pacman>,
+    puller: web::Data<Puller>,
+    cache_policy: web::Data<CachePolicy>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (arch, repo, file_name) = path.into_inner();
+    if file_name.starts_with('.') {
+        return Err(ErrorBadRequest("Invalid file name"));
+    }
+    if cache_policy.is_excluded(&file_name) {
+        return Err(ErrorNotFound("Not shared"));
+    }
+    let pacman = pacman.current();
+    let url = upstream_url(&repo, &arch, &file_name, &pacman.upstream_urls)?;
+    // Databases change all the time, so they are not worth keeping
+    if file_name.ends_with(".db")
+        || file_name.ends_with(".files")
+        || file_name.ends_with(".db.sig")
+        || file_name.ends_with(".files.sig")
+    {
+        return Ok(Redirect::to(url)
+            .temporary()
+            .respond_to(&request)
+            .map_into_boxed_body());
+    }
+    for cache_dir in &pacman.cache_dirs {
+        if let Ok(file) = NamedFile::open_async(cache_dir.join(&file_name)).await {
+            return Ok(file.use_last_modified(true).into_response(&request));
+        }
+    }
+    let Some(cache_dir) = pacman.cache_dirs.first() else {
+        return Err(ErrorInternalServerError("No cache directory"));
+    };
+
+    let response = CLIENT
+        .get(&url)
+        .send()
+        .await
+        .context(format!("Failed to request {}", url))
+        .map_err(ErrorBadGateway)?;
+    if !response.status().is_success() {
+        let status =
+            StatusCode::from_u16(response.status().as_u16()).map_err(ErrorInternalServerError)?;
+        return Ok(HttpResponse::build(status).finish());
+    }
+    let content_length = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    let (sender, receiver) = mpsc::channel(16);
+    if puller.claim(&file_name) {
+        spawn(save(
+            puller.clone(),
+            cache_dir.clone(),
+            file_name,
+            response,
+            sender,
+        ));
+    } else {
+        spawn(forward(response, sender));
+    }
+    let body = unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((Ok::<_, actix_web::Error>(chunk), receiver))
+    });
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/octet-stream");
+    Ok(match content_length {
+        Some(length) => builder.no_chunking(length).streaming(body),
+        None => builder.streaming(body),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Data;
+    use anyhow::Result;
+
+    use crate::{
+        auth::Auth,
+        config::ParentConfig,
+        parent::{Parents, Puller},
+    };
+
+    #[test]
+    fn candidates() -> Result<()> {
+        let config = ParentConfig {
+            address: Some("cachebox:8080".to_string()),
+            ..Default::default()
+        };
+        let parents = Parents::new(&config, "self".to_string(), Data::new(Auth::disabled()))?;
+        parents.insert_discovered("self".to_string(), "192.168.1.1".to_string(), 1052);
+        parents.insert_discovered("other".to_string(), "192.168.1.2".to_string(), 1052);
+        assert_eq!(
+            parents.candidates(),
+            vec![
+                ("cachebox".to_string(), "cachebox".to_string(), 8080),
+                ("other".to_string(), "192.168.1.2".to_string(), 1052),
+            ]
+        );
+        assert_eq!(parents.pull_url("x86_64", "core", "foo.pkg.tar.zst"), None);
+
+        let config = ParentConfig {
+            discover: false,
+            ..Default::default()
+        };
+        let parents = Parents::new(&config, "self".to_string(), Data::new(Auth::disabled()))?;
+        parents.insert_discovered("other".to_string(), "192.168.1.2".to_string(), 1052);
+        assert!(parents.candidates().is_empty());
+        Ok(())
+    }
+    #[test]
+    fn claim_once() {
+        let puller = Data::new(Puller::default());
+        assert!(puller.claim("foo.pkg.tar.zst"));
+        assert!(!puller.claim("foo.pkg.tar.zst"));
+        puller.release("foo.pkg.tar.zst");
+        assert!(puller.claim("foo.pkg.tar.zst"));
+    }
+}
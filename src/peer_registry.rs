@@ -0,0 +1,422 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use probe_cache::ProbeCache;
+use proximity::{Proximity, local_networks, proximity};
+use rand::{rng, seq::SliceRandom};
+
+use crate::tls::TlsEndpoint;
+
+mod probe_cache;
+pub mod proximity;
+
+/// Weight of a new sample in the moving averages.
+const SMOOTHING: f64 = 0.3;
+/// Smaller transfers are dominated by latency and say little about throughput.
+const THROUGHPUT_SAMPLE_MIN_SIZE: usize = 64 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Mdns,
+    Gossip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// Address or name the peer is reached at, which may differ from the name
+    /// it is known by.
+    pub host: String,
+    pub port: u16,
+    pub source: PeerSource,
+    /// Addresses the peer was discovered with, used to judge proximity.
+    pub addresses: Vec<IpAddr>,
+    pub site: Option<String>,
+    pub tls: Option<TlsEndpoint>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PeerStats {
+    /// Moving average of the round trip time of probes.
+    rtt: Option<Duration>,
+    /// Moving average of the transfer throughput in bytes per second.
+    throughput: Option<f64>,
+    /// Address the last probe actually connected to.
+    remote_address: Option<IpAddr>,
+    /// Until when the peer asked not to be sent downloads.
+    busy_until: Option<Instant>,
+}
+impl PeerStats {
+    /// Peers without measurements rank optimistically so they get measured.
+    fn rtt_bucket(&self) -> u32 {
+        self.rtt
+            .map_or(0, |rtt| (rtt.as_millis() as u64 + 1).ilog2())
+    }
+    fn throughput_bucket(&self) -> Reverse<u32> {
+        Reverse(self.throughput.map_or(u32::MAX, |throughput| {
+            ((throughput / 1024.0) as u64 + 1).ilog2()
+        }))
+    }
+}
+
+/// `host:port` for a URL, with IPv6 addresses in brackets.
+pub fn authority(host: &str, port: u16) -> String {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn smooth(average: Option<f64>, sample: f64) -> f64 {
+    match average {
+        Some(average) => average + SMOOTHING * (sample - average),
+        None => sample,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PeerRegistry {
+    site: Option<String>,
+    peers: Mutex<HashMap<String, Peer>>,
+    stats: Mutex<HashMap<String, PeerStats>>,
+    probes: Mutex<ProbeCache>,
+}
+impl PeerRegistry {
+    pub fn new(site: Option<String>) -> Self {
+        Self {
+            site,
+            ..Default::default()
+        }
+    }
+    pub fn insert(&self, host: String, peer: Peer) {
+        let mut peers = self.peers.lock().unwrap();
+        // A peer seen directly through mDNS must not be downgraded by a gossip entry
+        if peer.source == PeerSource::Gossip
+            && peers
+                .get(&host)
+                .is_some_and(|existing| existing.source == PeerSource::Mdns)
+        {
+            return;
+        }
+        // The peer may have restarted or moved, so earlier probe results are stale
+        self.probes.lock().unwrap().invalidate(&host);
+        peers.insert(host, peer);
+    }
+    /// Where the peer serves over TLS, if it does. `host` is either its name
+    /// or the host it is reached at.
+    pub fn tls(&self, host: &str) -> Option<TlsEndpoint> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .get(host)
+            .or_else(|| peers.values().find(|peer| peer.host == host))?
+            .tls
+            .clone()
+    }
+    /// The host the peer is reached at, if it is known.
+    pub fn host(&self, name: &str) -> Option<String> {
+        Some(self.peers.lock().unwrap().get(name)?.host.clone())
+    }
+    /// Every address the peer is known to use: the ones it was discovered
+    /// with, the one it is reached at and the one probes last connected to.
+    pub fn addresses(&self, name: &str) -> Vec<IpAddr> {
+        let Some(peer) = self.peers.lock().unwrap().get(name).cloned() else {
+            return Vec::new();
+        };
+        let mut addresses = peer.addresses;
+        addresses.extend(peer.host.parse::<IpAddr>());
+        addresses.extend(
+            self.stats
+                .lock()
+                .unwrap()
+                .get(name)
+                .and_then(|stats| stats.remote_address),
+        );
+        addresses
+    }
+    pub fn remove(&self, host: &str) {
+        self.peers.lock().unwrap().remove(host);
+        self.forget(host);
+    }
+    pub fn remove_from(&self, host: &str, source: PeerSource) {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.get(host).is_some_and(|peer| peer.source == source) {
+            peers.remove(host);
+            self.forget(host);
+        }
+    }
+    /// Replaces every peer from `source` with `peers`.
+    pub fn sync_source(&self, source: PeerSource, peers: Vec<(String, Peer)>) {
+        let stale = self
+            .snapshot()
+            .into_iter()
+            .filter(|(host, peer)| {
+                peer.source == source && peers.iter().all(|(new_host, _)| new_host != host)
+            })
+            .map(|(host, _)| host)
+            .collect::<Vec<_>>();
+        for host in stale {
+            self.remove_from(&host, source);
+        }
+        for (host, peer) in peers {
+            self.insert(host, peer);
+        }
+    }
+    fn forget(&self, host: &str) {
+        self.stats.lock().unwrap().remove(host);
+        self.probes.lock().unwrap().invalidate(host);
+    }
+    pub fn cached_probe(&self, host: &str, file_name: &str) -> Option<bool> {
+        self.probes
+            .lock()
+            .unwrap()
+            .get(host, file_name, Instant::now())
+    }
+    pub fn cache_probe(&self, host: &str, file_name: &str, exists: bool) {
+        self.probes
+            .lock()
+            .unwrap()
+            .insert(host, file_name, exists, Instant::now());
+    }
+    pub fn snapshot(&self) -> Vec<(String, Peer)> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(host, peer)| (host.clone(), peer.clone()))
+            .collect()
+    }
+    pub fn record_rtt(&self, host: &str, rtt: Duration, remote_address: Option<IpAddr>) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(host.to_string()).or_default();
+        let average = smooth(stats.rtt.map(|rtt| rtt.as_secs_f64()), rtt.as_secs_f64());
+        stats.rtt = Some(Duration::from_secs_f64(average));
+        if remote_address.is_some() {
+            stats.remote_address = remote_address;
+        }
+    }
+    /// Records a completed transfer from the peer, unless it is too small to
+    /// tell.
+    pub fn record_throughput(&self, host: &str, bytes: usize, elapsed: Duration) {
+        if bytes < THROUGHPUT_SAMPLE_MIN_SIZE {
+            return;
+        }
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(host.to_string()).or_default();
+        stats.throughput = Some(smooth(stats.throughput, sample));
+    }
+    /// The peer is over capacity, so it is skipped for a while without
+    /// forgetting it.
+    pub fn mark_busy(&self, host: &str, retry_after: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(host.to_string()).or_default();
+        stats.busy_until = Some(Instant::now() + retry_after);
+    }
+    /// Peers from best to worst: closest on the network first, then by latency
+    /// and throughput. Equally good peers come in random order to spread load.
+    /// Busy peers are left out.
+    pub fn ranked(&self) -> Vec<(String, Peer)> {
+        self.rank(&local_networks())
+    }
+    fn rank(&self, local_networks: &[IpNet]) -> Vec<(String, Peer)> {
+        let mut peers = self.snapshot();
+        peers.shuffle(&mut rng());
+        let stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        peers.retain(|(host, _)| {
+            stats
+                .get(host)
+                .and_then(|stats| stats.busy_until)
+                .is_none_or(|busy_until| busy_until <= now)
+        });
+        let key = |host: &str, peer: &Peer| -> (Proximity, u32, Reverse<u32>) {
+            let stats = stats.get(host).cloned().unwrap_or_default();
+            let mut addresses = peer.addresses.clone();
+            addresses.extend(stats.remote_address);
+            let proximity = proximity(
+                &addresses,
+                peer.site.as_deref(),
+                local_networks,
+                self.site.as_deref(),
+            );
+            (proximity, stats.rtt_bucket(), stats.throughput_bucket())
+        };
+        peers.sort_by_cached_key(|(host, peer)| key(host, peer));
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ipnet::IpNet;
+
+    use crate::{
+        peer_registry::{Peer, PeerRegistry, PeerSource, authority},
+        tls::TlsEndpoint,
+    };
+
+    fn peer(port: u16, source: PeerSource) -> Peer {
+        Peer {
+            host: "192.168.1.2".to_string(),
+            port,
+            source,
+            addresses: Vec::new(),
+            site: None,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn gossip_does_not_override_mdns() {
+        let registry = PeerRegistry::default();
+        let mdns = peer(1052, PeerSource::Mdns);
+        registry.insert("host".to_string(), mdns.clone());
+        registry.insert("host".to_string(), peer(1053, PeerSource::Gossip));
+        assert_eq!(registry.snapshot(), vec![("host".to_string(), mdns)]);
+    }
+    #[test]
+    fn remove_from_source() {
+        let registry = PeerRegistry::default();
+        registry.insert("host".to_string(), peer(1052, PeerSource::Mdns));
+        registry.remove_from("host", PeerSource::Gossip);
+        assert_eq!(registry.snapshot().len(), 1);
+        registry.remove_from("host", PeerSource::Mdns);
+        assert!(registry.snapshot().is_empty());
+    }
+    #[test]
+    fn sync_source() {
+        let registry = PeerRegistry::default();
+        registry.insert("a".to_string(), peer(1052, PeerSource::Mdns));
+        registry.insert("b".to_string(), peer(1052, PeerSource::Gossip));
+        registry.sync_source(
+            PeerSource::Mdns,
+            vec![("c".to_string(), peer(1052, PeerSource::Mdns))],
+        );
+        let mut snapshot = registry.snapshot();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            snapshot,
+            vec![
+                ("b".to_string(), peer(1052, PeerSource::Gossip)),
+                ("c".to_string(), peer(1052, PeerSource::Mdns)),
+            ]
+        );
+    }
+    #[test]
+    fn authority_brackets_ipv6() {
+        assert_eq!(authority("192.168.1.2", 1052), "192.168.1.2:1052");
+        assert_eq!(authority("fd00::1", 1052), "[fd00::1]:1052");
+        assert_eq!(authority("foo.local", 1052), "foo.local:1052");
+    }
+    #[test]
+    fn tls_by_name_or_host() {
+        let registry = PeerRegistry::default();
+        let mut tls = peer(1052, PeerSource::Mdns);
+        tls.tls = Some(TlsEndpoint {
+            port: 1053,
+            fingerprint: "fingerprint".to_string(),
+        });
+        registry.insert("host #2".to_string(), tls.clone());
+        assert_eq!(registry.tls("host #2"), tls.tls);
+        assert_eq!(registry.tls("192.168.1.2"), tls.tls);
+        assert_eq!(registry.tls("host"), None);
+        assert_eq!(registry.host("host #2").as_deref(), Some("192.168.1.2"));
+    }
+    #[test]
+    fn changes_invalidate_probes() {
+        let registry = PeerRegistry::default();
+        registry.insert("host".to_string(), peer(1052, PeerSource::Mdns));
+        registry.cache_probe("host", "foo.pkg.tar.zst", true);
+        assert_eq!(registry.cached_probe("host", "foo.pkg.tar.zst"), Some(true));
+        registry.insert("host".to_string(), peer(1053, PeerSource::Mdns));
+        assert_eq!(registry.cached_probe("host", "foo.pkg.tar.zst"), None);
+
+        registry.cache_probe("host", "foo.pkg.tar.zst", false);
+        registry.remove("host");
+        assert_eq!(registry.cached_probe("host", "foo.pkg.tar.zst"), None);
+    }
+    #[test]
+    fn rank_by_proximity_then_measurements() {
+        let registry = PeerRegistry::new(Some("office".to_string()));
+        let networks = vec!["192.168.1.0/24".parse::<IpNet>().unwrap()];
+        let mut remote = peer(1052, PeerSource::Gossip);
+        remote.addresses = vec!["10.1.0.1".parse().unwrap()];
+        let mut same_site = remote.clone();
+        same_site.site = Some("office".to_string());
+        let mut wired = peer(1052, PeerSource::Mdns);
+        wired.addresses = vec!["192.168.1.2".parse().unwrap()];
+        let wifi = wired.clone();
+        registry.insert("remote".to_string(), remote);
+        registry.insert("same-site".to_string(), same_site);
+        registry.insert("wired".to_string(), wired);
+        registry.insert("wifi".to_string(), wifi);
+        registry.record_rtt("wired", Duration::from_millis(1), None);
+        registry.record_rtt("wifi", Duration::from_millis(40), None);
+        registry.record_rtt("remote", Duration::from_millis(1), None);
+
+        let ranked = registry
+            .rank(&networks)
+            .into_iter()
+            .map(|(host, _)| host)
+            .collect::<Vec<_>>();
+        assert_eq!(ranked, vec!["wired", "wifi", "same-site", "remote"]);
+    }
+    #[test]
+    fn rank_by_throughput() {
+        let registry = PeerRegistry::default();
+        registry.insert("slow".to_string(), peer(1052, PeerSource::Mdns));
+        registry.insert("fast".to_string(), peer(1052, PeerSource::Mdns));
+        for host in ["slow", "fast"] {
+            registry.record_rtt(host, Duration::from_millis(2), None);
+        }
+        registry.record_throughput("slow", 1 << 20, Duration::from_secs(1));
+        registry.record_throughput("fast", 1 << 20, Duration::from_millis(10));
+        assert_eq!(registry.rank(&[])[0].0, "fast");
+    }
+    #[test]
+    fn remote_address_counts_for_proximity() {
+        let registry = PeerRegistry::default();
+        let networks = vec!["192.168.1.0/24".parse::<IpNet>().unwrap()];
+        registry.insert("a".to_string(), peer(1052, PeerSource::Gossip));
+        registry.insert("b".to_string(), peer(1052, PeerSource::Gossip));
+        registry.record_rtt("a", Duration::from_millis(50), None);
+        registry.record_rtt(
+            "b",
+            Duration::from_millis(50),
+            Some("192.168.1.7".parse().unwrap()),
+        );
+        assert_eq!(registry.rank(&networks)[0].0, "b");
+    }
+    #[test]
+    fn busy_peers_are_skipped() {
+        let registry = PeerRegistry::default();
+        registry.insert("busy".to_string(), peer(1052, PeerSource::Mdns));
+        registry.insert("idle".to_string(), peer(1052, PeerSource::Mdns));
+        registry.mark_busy("busy", Duration::from_secs(60));
+        let ranked = registry.rank(&[]);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, "idle");
+        registry.mark_busy("busy", Duration::ZERO);
+        assert_eq!(registry.rank(&[]).len(), 2);
+    }
+    #[test]
+    fn small_transfers_are_ignored() {
+        let registry = PeerRegistry::default();
+        registry.insert("steady".to_string(), peer(1052, PeerSource::Mdns));
+        registry.insert("slow".to_string(), peer(1052, PeerSource::Mdns));
+        for host in ["steady", "slow"] {
+            registry.record_rtt(host, Duration::from_millis(2), None);
+        }
+        registry.record_throughput("steady", 1 << 20, Duration::from_millis(100));
+        registry.record_throughput("slow", 1 << 20, Duration::from_secs(1));
+        registry.record_throughput("slow", 60 << 10, Duration::from_micros(1));
+        assert_eq!(registry.rank(&[])[0].0, "steady");
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// A peer that has a file keeps it until its cache is cleaned.
+const POSITIVE_TTL: Duration = Duration::from_secs(60);
+/// A peer that misses a file may download it any moment.
+const NEGATIVE_TTL: Duration = Duration::from_secs(10);
+/// Expired entries are only swept once the cache grows past this.
+const SWEEP_THRESHOLD: usize = 4096;
+
+/// Recent results of asking a peer whether it has a file.
+#[derive(Debug, Default)]
+pub struct ProbeCache {
+    entries: HashMap<(String, String), (bool, Instant)>,
+}
+impl ProbeCache {
+    pub fn get(&self, host: &str, file_name: &str, now: Instant) -> Option<bool> {
+        let &(exists, expires_at) = self
+            .entries
+            .get(&(host.to_string(), file_name.to_string()))?;
+        (now < expires_at).then_some(exists)
+    }
+    pub fn insert(&mut self, host: &str, file_name: &str, exists: bool, now: Instant) {
+        if self.entries.len() >= SWEEP_THRESHOLD {
+            self.entries.retain(|_, (_, expires_at)| now < *expires_at);
+        }
+        let ttl = if exists { POSITIVE_TTL } else { NEGATIVE_TTL };
+        self.entries.insert(
+            (host.to_string(), file_name.to_string()),
+            (exists, now + ttl),
+        );
+    }
+    pub fn invalidate(&mut self, host: &str) {
+        self.entries
+            .retain(|(cached_host, _), _| cached_host != host);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::peer_registry::probe_cache::{NEGATIVE_TTL, POSITIVE_TTL, ProbeCache};
+
+    #[test]
+    fn expires() {
+        let now = Instant::now();
+        let mut cache = ProbeCache::default();
+        cache.insert("a", "foo.pkg.tar.zst", true, now);
+        cache.insert("a", "bar.pkg.tar.zst", false, now);
+        assert_eq!(cache.get("a", "foo.pkg.tar.zst", now), Some(true));
+        assert_eq!(cache.get("a", "bar.pkg.tar.zst", now), Some(false));
+        assert_eq!(cache.get("b", "foo.pkg.tar.zst", now), None);
+
+        let later = now + NEGATIVE_TTL + Duration::from_secs(1);
+        assert_eq!(cache.get("a", "foo.pkg.tar.zst", later), Some(true));
+        assert_eq!(cache.get("a", "bar.pkg.tar.zst", later), None);
+        assert_eq!(cache.get("a", "foo.pkg.tar.zst", now + POSITIVE_TTL), None);
+    }
+    #[test]
+    fn invalidate() {
+        let now = Instant::now();
+        let mut cache = ProbeCache::default();
+        cache.insert("a", "foo.pkg.tar.zst", true, now);
+        cache.insert("b", "foo.pkg.tar.zst", true, now);
+        cache.invalidate("a");
+        assert_eq!(cache.get("a", "foo.pkg.tar.zst", now), None);
+        assert_eq!(cache.get("b", "foo.pkg.tar.zst", now), Some(true));
+    }
+}
@@ -0,0 +1,106 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use log::debug;
+use nix::{ifaddrs::getifaddrs, net::if_::InterfaceFlags, sys::socket::SockaddrStorage};
+
+/// How close a peer is on the network, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Proximity {
+    SameSubnet,
+    SameSite,
+    Remote,
+}
+
+fn ip_of(address: &SockaddrStorage) -> Option<IpAddr> {
+    if let Some(address) = address.as_sockaddr_in() {
+        return Some(IpAddr::V4(address.ip()));
+    }
+    address
+        .as_sockaddr_in6()
+        .map(|address| IpAddr::V6(address.ip()))
+}
+
+/// Networks directly attached to this host with their interface names. Looked
+/// up on every call since addresses come and go with DHCP and roaming.
+pub fn interface_networks() -> Vec<(String, IpNet)> {
+    let addresses = match getifaddrs() {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            debug!("Failed to get interface addresses: {e}");
+            return Vec::new();
+        }
+    };
+    addresses
+        .filter(|address| !address.flags.contains(InterfaceFlags::IFF_LOOPBACK))
+        .filter_map(|address| {
+            let ip = ip_of(address.address.as_ref()?)?;
+            let netmask = ip_of(address.netmask.as_ref()?)?;
+            let network = IpNet::with_netmask(ip, netmask).ok()?;
+            Some((address.interface_name, network))
+        })
+        .collect()
+}
+
+pub fn local_networks() -> Vec<IpNet> {
+    interface_networks()
+        .into_iter()
+        .map(|(_, network)| network)
+        .collect()
+}
+
+pub fn proximity(
+    addresses: &[IpAddr],
+    site: Option<&str>,
+    local_networks: &[IpNet],
+    local_site: Option<&str>,
+) -> Proximity {
+    if addresses.iter().any(|address| {
+        local_networks
+            .iter()
+            .any(|network| network.contains(address))
+    }) {
+        Proximity::SameSubnet
+    } else if site.is_some() && site == local_site {
+        Proximity::SameSite
+    } else {
+        Proximity::Remote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use ipnet::IpNet;
+
+    use crate::peer_registry::proximity::{Proximity, proximity};
+
+    #[test]
+    fn classify() {
+        let networks = ["192.168.1.10/24", "fd00:1::10/64"]
+            .iter()
+            .map(|network| network.parse::<IpNet>().unwrap())
+            .collect::<Vec<_>>();
+        let address = |address: &str| address.parse::<IpAddr>().unwrap();
+
+        let same_subnet = [address("10.0.0.1"), address("fd00:1::20")];
+        assert_eq!(
+            proximity(&same_subnet, None, &networks, Some("office")),
+            Proximity::SameSubnet
+        );
+        let other_subnet = [address("192.168.2.20")];
+        assert_eq!(
+            proximity(&other_subnet, Some("office"), &networks, Some("office")),
+            Proximity::SameSite
+        );
+        assert_eq!(
+            proximity(&other_subnet, Some("lab"), &networks, Some("office")),
+            Proximity::Remote
+        );
+        assert_eq!(
+            proximity(&other_subnet, None, &networks, None),
+            Proximity::Remote
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use futures::{StreamExt, stream::select_all};
+use log::{debug, warn};
+use zbus::{
+    Connection,
+    fdo::{DBusProxy, PropertiesProxy},
+    proxy::CacheProperties,
+};
+use zbus_binding::{DisplayDeviceProxy, UPowerProxy};
+
+use crate::{
+    activity::{Activity, PauseReason},
+    config::PowerConfig,
+};
+
+mod zbus_binding;
+
+const DESTINATION: &str = "org.freedesktop.UPower";
+const PATH: &str = "/org/freedesktop/UPower";
+const DEVICE_INTERFACE: &str = "org.freedesktop.UPower.Device";
+const DISPLAY_DEVICE_PATH: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+
+struct PowerState {
+    on_battery: bool,
+    percentage: f64,
+}
+
+async fn read_state(connection: &Connection) -> Result<PowerState> {
+    let upower = UPowerProxy::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let display_device = DisplayDeviceProxy::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    Ok(PowerState {
+        on_battery: upower.on_battery().await?,
+        percentage: display_device.percentage().await?,
+    })
+}
+
+/// Pauses serving according to the policy whenever the battery state UPower
+/// reports changes. Without UPower, serving is never paused.
+pub async fn watch(config: PowerConfig, activity: &Activity) {
+    let changes = async {
+        let connection = Connection::system().await?;
+        let dbus = DBusProxy::new(&connection).await?;
+        let owner_changes = dbus
+            .receive_name_owner_changed_with_args(&[(0, DESTINATION)])
+            .await?
+            .map(|_| ())
+            .boxed();
+        let mut changes = vec![owner_changes];
+        for (path, interface) in [(PATH, DESTINATION), (DISPLAY_DEVICE_PATH, DEVICE_INTERFACE)] {
+            let properties = PropertiesProxy::builder(&connection)
+                .destination(DESTINATION)?
+                .path(path)?
+                .build()
+                .await?;
+            let property_changes = properties
+                .receive_properties_changed_with_args(&[(0, interface)])
+                .await?
+                .map(|_| ())
+                .boxed();
+            changes.push(property_changes);
+        }
+        anyhow::Ok((connection, select_all(changes)))
+    };
+    let (connection, mut changes) = match changes.await {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn!("Failed to watch UPower: {e:#}");
+            return;
+        }
+    };
+    let mut paused = false;
+    loop {
+        paused = match read_state(&connection).await {
+            Ok(state) => config.pauses(state.on_battery, state.percentage, paused),
+            Err(e) => {
+                debug!("UPower is unavailable: {e:#}");
+                false
+            }
+        };
+        activity.set(PauseReason::OnBattery, paused);
+        if changes.next().await.is_none() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! # D-Bus interface proxies for the parts of UPower cacheman uses
+//!
+//! Adapted from the introspection data of `org.freedesktop.UPower` and
+//! `org.freedesktop.UPower.Device`.
+use zbus::proxy;
+
+#[proxy(
+    interface = "org.freedesktop.UPower",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower"
+)]
+pub trait UPower {
+    /// OnBattery property
+    #[zbus(property)]
+    fn on_battery(&self) -> zbus::Result<bool>;
+}
+
+/// The composite battery UPower shows in desktop environments.
+#[proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower/devices/DisplayDevice"
+)]
+pub trait DisplayDevice {
+    /// Percentage property
+    #[zbus(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+}
@@ -1,34 +1,145 @@
-use std::{collections::HashMap, sync::Mutex, time::Duration};
+use std::{
+    collections::HashMap,
+    pin::pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
 use actix_web::{
-    error::ErrorInternalServerError,
+    HttpRequest, HttpResponse, Responder,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::{ErrorBadGateway, ErrorInternalServerError},
     get,
+    middleware::Next,
     web::{self, Redirect},
 };
 use anyhow::Context;
-use reqwest::StatusCode;
+use futures::stream::unfold;
+use log::debug;
+use reqwest::{
+    StatusCode,
+    header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE, RETRY_AFTER},
+};
+use serde::Serialize;
+use tokio::{select, time::sleep};
+
+use crate::{
+    CLIENT,
+    activity::{Activity, Scope},
+    auth::Auth,
+    config::{Config, LiveProxyConfig, ProxyMode},
+    lease::{LeaseHolder, Leases},
+    pacman::Pacman,
+    parent::Parents,
+    peer_registry::{Peer, PeerRegistry, authority},
+    tls::{TlsEndpoint, peer_client},
+};
 
-use crate::CLIENT;
+/// Sent with requests between peers; an empty value means no cluster.
+pub const CLUSTER_HEADER: &str = "X-Cacheman-Cluster";
+/// How often to check whether the peer fetching a file has finished.
+const FETCHER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long to skip a busy peer that did not say.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Longest a busy peer is skipped, whatever it asks.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Answers 403 to requests tagged with another cluster, like the endpoints
+/// checking it themselves. Untagged requests are let through.
+pub async fn check_cluster(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let config = request
+        .app_data::<web::Data<Config>>()
+        .cloned()
+        .expect("Config is registered as app data");
+    let cluster = config.discovery.cluster.as_deref().unwrap_or("");
+    if request
+        .headers()
+        .get(CLUSTER_HEADER)
+        .is_some_and(|value| value.as_bytes() != cluster.as_bytes())
+    {
+        let response = HttpResponse::Forbidden().body("Different cluster");
+        return Ok(request.into_response(response));
+    }
+    Ok(next.call(request).await?.map_into_boxed_body())
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum PeerFileStatus {
     Exists,
     NotFound,
+    /// Over capacity for now; tried again later.
+    Busy,
     PeerError,
 }
 
-async fn check_file_exists(peer: &str, port: u16, file_name: &str) -> PeerFileStatus {
-    let url = format!("http://{}:{}/cache/{}", peer, port, file_name);
-    let response = CLIENT
+/// Where the peer serves its cache: over TLS when it offers it.
+fn peer_base_url(host: &str, port: u16, tls: Option<&TlsEndpoint>) -> String {
+    match tls {
+        Some(tls) => format!("https://{}", authority(host, tls.port)),
+        None => format!("http://{}", authority(host, port)),
+    }
+}
+
+async fn check_file_exists(
+    peer_registry: &PeerRegistry,
+    auth: &Auth,
+    peer: &str,
+    base_url: &str,
+    file_name: &str,
+    cluster: Option<&str>,
+) -> PeerFileStatus {
+    if let Some(exists) = peer_registry.cached_probe(peer, file_name) {
+        return if exists {
+            PeerFileStatus::Exists
+        } else {
+            PeerFileStatus::NotFound
+        };
+    }
+    probe_file(peer_registry, auth, peer, base_url, file_name, cluster).await
+}
+async fn probe_file(
+    peer_registry: &PeerRegistry,
+    auth: &Auth,
+    peer: &str,
+    base_url: &str,
+    file_name: &str,
+    cluster: Option<&str>,
+) -> PeerFileStatus {
+    let url = auth.sign_url(format!("{}/cache/{}", base_url, file_name));
+    let start = Instant::now();
+    let response = peer_client(&url)
         .head(&url)
+        .header(CLUSTER_HEADER, cluster.unwrap_or(""))
         .timeout(Duration::from_secs(1))
         .send()
         .await;
     match response {
         Ok(resp) => {
-            if resp.status() == StatusCode::NOT_FOUND {
+            let remote_address = resp.remote_addr().map(|address| address.ip());
+            peer_registry.record_rtt(peer, start.elapsed(), remote_address);
+            let status = resp.status();
+            let retry_after = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+                .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+            if let Err(e) = auth.read_verified(peer, &url, resp).await {
+                debug!("{:#}", e);
+                return PeerFileStatus::PeerError;
+            }
+            if status == StatusCode::NOT_FOUND {
+                peer_registry.cache_probe(peer, file_name, false);
                 PeerFileStatus::NotFound
-            } else if resp.status().is_success() {
+            } else if status == StatusCode::SERVICE_UNAVAILABLE {
+                debug!("{} is busy", peer);
+                peer_registry.mark_busy(peer, retry_after.min(MAX_RETRY_AFTER));
+                PeerFileStatus::Busy
+            } else if status.is_success() {
+                peer_registry.cache_probe(peer, file_name, true);
                 PeerFileStatus::Exists
             } else {
                 PeerFileStatus::PeerError
@@ -38,66 +149,439 @@ async fn check_file_exists(peer: &str, port: u16, file_name: &str) -> PeerFileSt
     }
 }
 
-#[get("/{arch}/{repo}/{file_name}")]
-async fn service_proxy(
-    path: web::Path<(String, String, String)>,
-    peer_list: web::Data<Mutex<HashMap<String, u16>>>,
-    upstream_urls: web::Data<HashMap<String, Vec<String>>>,
-) -> Result<Redirect, actix_web::Error> {
-    let (arch, repo, file_name) = path.as_ref();
-    if file_name.ends_with(".db")
-        || file_name.ends_with(".files")
-        || file_name.ends_with(".db.sig")
-        || file_name.ends_with(".files.sig")
-    {
-        return redirect_to_upstream(repo, arch, file_name, &upstream_urls).await;
+#[derive(Debug, Default)]
+pub struct HedgeStats {
+    requests: AtomicU64,
+    hedged: AtomicU64,
+    peer_wins: AtomicU64,
+    upstream_wins: AtomicU64,
+}
+impl HedgeStats {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
     }
-    let cloned_peer_list = peer_list.lock().unwrap().clone();
-    for (peer, port) in cloned_peer_list.into_iter() {
+}
+
+#[derive(Debug, Serialize)]
+struct HedgeStatus {
+    /// Package requests looked up on peers.
+    requests: u64,
+    /// Requests for which upstream was raced against the peers.
+    hedged: u64,
+    peer_wins: u64,
+    upstream_wins: u64,
+}
+
+#[get("/status")]
+async fn service_proxy_status(stats: web::Data<HedgeStats>) -> web::Json<HedgeStatus> {
+    web::Json(HedgeStatus {
+        requests: stats.requests.load(Ordering::Relaxed),
+        hedged: stats.hedged.load(Ordering::Relaxed),
+        peer_wins: stats.peer_wins.load(Ordering::Relaxed),
+        upstream_wins: stats.upstream_wins.load(Ordering::Relaxed),
+    })
+}
+
+/// A pacman mirrorlist snippet pointing at this node, for machines without
+/// cacheman of their own.
+#[get("/mirrorlist")]
+async fn service_mirrorlist(
+    request: HttpRequest,
+    config: web::Data<Config>,
+    proxy: web::Data<LiveProxyConfig>,
+) -> impl Responder {
+    let connection_info = request.connection_info();
+    let base = format!("{}://{}", connection_info.scheme(), connection_info.host());
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(mirrorlist(&base, &config, proxy.current().mode))
+}
+
+fn mirrorlist(base: &str, config: &Config, mode: ProxyMode) -> String {
+    let mut snippet =
+        String::from("# Generated by cacheman. Include this before any other server.\n");
+    // pacman cannot sign its requests, which /cache requires with auth
+    if config.node.serves_cache() && !config.auth.enabled {
+        snippet.push_str(&format!("CacheServer = {}/cache\n", base));
+    }
+    // Misses are left to the mirrors that follow in peers-only mode
+    let key = match mode {
+        ProxyMode::Full => "Server",
+        ProxyMode::PeersOnly => "CacheServer",
+    };
+    snippet.push_str(&format!("{} = {}/proxy/$arch/$repo\n", key, base));
+    snippet
+}
+
+async fn check_upstream(url: &str) -> bool {
+    let Ok(response) = CLIENT
+        .head(url)
+        .timeout(Duration::from_secs(3))
+        .send()
+        .await
+    else {
+        return false;
+    };
+    response.status().is_success()
+}
+
+/// Returns the first peer, best ranked first, that has the file and its
+/// signature, along with the URL of the file on it.
+async fn find_on_peers(
+    peer_registry: &PeerRegistry,
+    auth: &Auth,
+    file_name: &str,
+    cluster: Option<&str>,
+) -> Option<(String, String)> {
+    for (
+        peer,
+        Peer {
+            host, port, tls, ..
+        },
+    ) in peer_registry.ranked()
+    {
+        let base_url = peer_base_url(&host, port, tls.as_ref());
         if !file_name.ends_with(".sig") {
-            let status = check_file_exists(&peer, port, &format!("{file_name}.sig")).await;
+            let sig_name = format!("{file_name}.sig");
+            let status =
+                check_file_exists(peer_registry, auth, &peer, &base_url, &sig_name, cluster).await;
             match status {
                 PeerFileStatus::Exists => {}
-                PeerFileStatus::NotFound => {
+                PeerFileStatus::NotFound | PeerFileStatus::Busy => {
                     continue;
                 }
                 PeerFileStatus::PeerError => {
-                    peer_list.lock().unwrap().remove(&peer);
+                    peer_registry.remove(&peer);
                     continue;
                 }
             }
         }
-        let status = check_file_exists(&peer, port, file_name).await;
+        let status =
+            check_file_exists(peer_registry, auth, &peer, &base_url, file_name, cluster).await;
         match status {
             PeerFileStatus::Exists => {
-                let url = format!("http://{}:{}/cache/{}", peer, port, file_name);
-                return Ok(Redirect::to(url).temporary());
+                let url = auth.sign_url(format!("{}/cache/{}", base_url, file_name));
+                return Some((peer, url));
             }
-            PeerFileStatus::NotFound => {}
+            PeerFileStatus::NotFound | PeerFileStatus::Busy => {}
             PeerFileStatus::PeerError => {
-                peer_list.lock().unwrap().remove(&peer);
+                peer_registry.remove(&peer);
             }
         }
     }
-    redirect_to_upstream(repo, arch, file_name, &upstream_urls).await
+    None
 }
-async fn redirect_to_upstream(
+
+#[get("/{arch}/{repo}/{file_name}")]
+#[allow(clippy::too_many_arguments)]
+async fn service_proxy(
+    request: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    peer_registry: web::Data<PeerRegistry>,
+    pacman: web::Data<Pacman>,
+    config: web::Data<Config>,
+    proxy: web::Data<LiveProxyConfig>,
+    stats: web::Data<HedgeStats>,
+    leases: web::Data<Leases>,
+    parents: web::Data<Parents>,
+    auth: web::Data<Auth>,
+    activity: web::Data<Activity>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (arch, repo, file_name) = path.as_ref();
+    let cluster = config.discovery.cluster.as_deref();
+    let pacman = pacman.current();
+    let upstream_urls = &pacman.upstream_urls;
+    let proxy = proxy.current();
+    // pacman goes on with its own mirrors after a 404 from a CacheServer
+    let peers_only = proxy.mode == ProxyMode::PeersOnly;
+    if file_name.ends_with(".db")
+        || file_name.ends_with(".files")
+        || file_name.ends_with(".db.sig")
+        || file_name.ends_with(".files.sig")
+    {
+        if peers_only {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        return redirect_to_upstream(&request, repo, arch, file_name, upstream_urls);
+    }
+    HedgeStats::count(&stats.requests);
+    // A paused node leaves its peers and parent alone
+    let paused = activity.is_paused(Scope::Lan);
+    // A parent cache stands in for upstream; it pulls files through itself, so
+    // it can always be raced against peers and fetches each file only once
+    let parent_url = (!paused)
+        .then(|| parents.pull_url(arch, repo, file_name))
+        .flatten();
+    let mut peers = pin!(async {
+        if paused {
+            return None;
+        }
+        find_on_peers(&peer_registry, &auth, file_name, cluster).await
+    });
+    let mut peer_url = None;
+    let mut upstream = None;
+    if let Some(delay) = proxy.hedge_delay() {
+        select! {
+            url = &mut peers => peer_url = Some(url),
+            _ = sleep(delay) => {
+                upstream = match &parent_url {
+                    Some(url) => Some(url.clone()),
+                    None => upstream_url(repo, arch, file_name, upstream_urls).ok(),
+                };
+            }
+        }
+    }
+    let peer_url = match (peer_url, upstream) {
+        (Some(url), _) => url,
+        // No peer confirmed within the hedge delay, so upstream races the remaining probes
+        (None, Some(upstream)) => {
+            HedgeStats::count(&stats.hedged);
+            select! {
+                url = &mut peers => {
+                    if url.is_some() {
+                        HedgeStats::count(&stats.peer_wins);
+                    }
+                    url
+                }
+                true = async { parent_url.is_some() || check_upstream(&upstream).await } => {
+                    HedgeStats::count(&stats.upstream_wins);
+                    return Ok(redirect(&request, upstream));
+                }
+            }
+        }
+        (None, None) => peers.await,
+    };
+    let handed_over = match (peer_url, parent_url) {
+        (Some((peer, url)), _) => {
+            hand_over(&request, url, cluster, Some((&peer_registry, &peer))).await
+        }
+        (None, Some(url)) => hand_over(&request, url, cluster, None).await,
+        (None, None) => None,
+    };
+    if let Some(response) = handed_over {
+        return Ok(response);
+    }
+    let fetcher_url = match proxy.dedup_wait().filter(|_| !paused) {
+        Some(wait) => {
+            wait_for_fetcher(&leases, &peer_registry, &auth, file_name, cluster, wait).await
+        }
+        None => None,
+    };
+    redirect_to_peer_or_upstream(
+        &request,
+        &peer_registry,
+        fetcher_url,
+        cluster,
+        peers_only,
+        repo,
+        arch,
+        file_name,
+        upstream_urls,
+    )
+    .await
+}
+/// Every peer missed the file, so only one node of the LAN fetches it from
+/// upstream. Returns the fetching peer and the URL of the file on it once it
+/// has the file, or `None` if this node should fetch it itself or nobody else
+/// does.
+async fn wait_for_fetcher(
+    leases: &Leases,
+    peer_registry: &PeerRegistry,
+    auth: &Auth,
+    file_name: &str,
+    cluster: Option<&str>,
+    wait: Duration,
+) -> Option<(String, String)> {
+    let peers = peer_registry.snapshot();
+    // A package and its signature are fetched together
+    let package = file_name.strip_suffix(".sig").unwrap_or(file_name);
+    let holder = match leases.acquire(&peers, package).await {
+        Ok(Some(holder)) => holder,
+        Ok(None) => return None,
+        Err(e) => {
+            debug!("Failed to acquire a lease for {}: {:#}", package, e);
+            return None;
+        }
+    };
+    if leases.is_own(&holder) {
+        return None;
+    }
+    let LeaseHolder { holder, port } = holder;
+    let host = peer_registry
+        .host(&holder)
+        .unwrap_or_else(|| holder.clone());
+    let base_url = peer_base_url(&host, port, peer_registry.tls(&holder).as_ref());
+    let deadline = Instant::now() + wait;
+    while Instant::now() < deadline {
+        sleep(FETCHER_POLL_INTERVAL).await;
+        if !file_name.ends_with(".sig") {
+            let sig_name = format!("{file_name}.sig");
+            let status =
+                probe_file(peer_registry, auth, &holder, &base_url, &sig_name, cluster).await;
+            if status != PeerFileStatus::Exists {
+                continue;
+            }
+        }
+        let status = probe_file(peer_registry, auth, &holder, &base_url, file_name, cluster).await;
+        if status == PeerFileStatus::Exists {
+            let url = auth.sign_url(format!("{}/cache/{}", base_url, file_name));
+            return Some((holder, url));
+        }
+    }
+    debug!("{} did not fetch {} in time", holder, file_name);
+    None
+}
+pub fn upstream_url(
     repo: &str,
     arch: &str,
     file_name: &str,
     upstream_urls: &HashMap<String, Vec<String>>,
-) -> Result<Redirect, actix_web::Error> {
+) -> Result<String, actix_web::Error> {
     let upstream_urls = upstream_urls
         .get(repo)
         .context(format!("Repository {} is not available", repo))
         .map_err(ErrorInternalServerError)?;
     let upstream_url = upstream_urls
-        .get(0)
+        .first()
         .context(format!(
             "Repository {} is available but no upstream found",
             repo
         ))
         .map_err(ErrorInternalServerError)?;
-    let real_url = upstream_url.replace("$repo", &repo).replace("$arch", &arch) + "/" + &file_name;
-    Ok(Redirect::to(real_url).temporary())
+    Ok(upstream_url.replace("$repo", repo).replace("$arch", arch) + "/" + file_name)
+}
+fn redirect(request: &HttpRequest, url: String) -> HttpResponse {
+    Redirect::to(url)
+        .temporary()
+        .respond_to(request)
+        .map_into_boxed_body()
+}
+/// Pacman cannot verify the self-signed certificate of a peer, so files on
+/// peers reached over TLS are relayed rather than redirected to, keeping the
+/// method and range of the request. The throughput of a relayed transfer from
+/// `peer` is recorded once it completes. Returns `None` if the peer fails to
+/// answer, leaving the request to the next source.
+async fn hand_over(
+    request: &HttpRequest,
+    url: String,
+    cluster: Option<&str>,
+    peer: Option<(&web::Data<PeerRegistry>, &str)>,
+) -> Option<HttpResponse> {
+    if !url.starts_with("https://") {
+        return Some(redirect(request, url));
+    }
+    let method = reqwest::Method::from_bytes(request.method().as_str().as_bytes()).ok()?;
+    let start = Instant::now();
+    let mut relayed = peer_client(&url)
+        .request(method, &url)
+        .header(CLUSTER_HEADER, cluster.unwrap_or(""));
+    if let Some(range) = request.headers().get(RANGE.as_str()) {
+        relayed = relayed.header(RANGE, range.as_bytes());
+    }
+    let response = match relayed
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+    {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("Failed to fetch {}: {}", url, e);
+            return None;
+        }
+    };
+    let status = actix_web::http::StatusCode::from_u16(response.status().as_u16()).ok()?;
+    let mut builder = HttpResponse::build(status);
+    builder.content_type("application/octet-stream");
+    if let Some(content_range) = response.headers().get(CONTENT_RANGE) {
+        builder.insert_header((CONTENT_RANGE.as_str(), content_range.as_bytes()));
+    }
+    let length = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    if let Some(length) = length {
+        builder.no_chunking(length);
+    }
+    let peer = peer.map(|(peer_registry, peer)| (peer_registry.clone(), peer.to_string()));
+    let body = unfold((Some(response), 0), move |(response, bytes)| {
+        let peer = peer.clone();
+        async move {
+            let mut response = response?;
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    let bytes = bytes + chunk.len();
+                    Some((Ok(chunk), (Some(response), bytes)))
+                }
+                Ok(None) => {
+                    if let Some((peer_registry, peer)) = peer {
+                        peer_registry.record_throughput(&peer, bytes, start.elapsed());
+                    }
+                    None
+                }
+                Err(e) => Some((Err(ErrorBadGateway(e)), (None, bytes))),
+            }
+        }
+    });
+    Some(builder.streaming(body))
+}
+/// Hands over the file on the peer if there is one, or else leaves it to
+/// upstream: pacman is redirected there, or told 404 in peers-only mode.
+#[allow(clippy::too_many_arguments)]
+async fn redirect_to_peer_or_upstream(
+    request: &HttpRequest,
+    peer_registry: &web::Data<PeerRegistry>,
+    peer_url: Option<(String, String)>,
+    cluster: Option<&str>,
+    peers_only: bool,
+    repo: &str,
+    arch: &str,
+    file_name: &str,
+    upstream_urls: &HashMap<String, Vec<String>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Some((peer, url)) = peer_url
+        && let Some(response) = hand_over(request, url, cluster, Some((peer_registry, &peer))).await
+    {
+        return Ok(response);
+    }
+    if peers_only {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    redirect_to_upstream(request, repo, arch, file_name, upstream_urls)
+}
+fn redirect_to_upstream(
+    request: &HttpRequest,
+    repo: &str,
+    arch: &str,
+    file_name: &str,
+    upstream_urls: &HashMap<String, Vec<String>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let real_url = upstream_url(repo, arch, file_name, upstream_urls)?;
+    Ok(redirect(request, real_url))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{AuthConfig, Config, ProxyMode},
+        service::mirrorlist,
+    };
+
+    #[test]
+    fn mirrorlist_without_cache_under_auth() {
+        let base = "http://192.168.1.2:1052";
+        let mut config = Config::default();
+        assert_eq!(
+            mirrorlist(base, &config, ProxyMode::Full),
+            "# Generated by cacheman. Include this before any other server.\n\
+            CacheServer = http://192.168.1.2:1052/cache\n\
+            Server = http://192.168.1.2:1052/proxy/$arch/$repo\n"
+        );
+        config.auth = AuthConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let snippet = mirrorlist(base, &config, ProxyMode::PeersOnly);
+        assert!(!snippet.contains("/cache"));
+        assert!(snippet.ends_with("CacheServer = http://192.168.1.2:1052/proxy/$arch/$repo\n"));
+    }
 }
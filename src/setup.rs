@@ -0,0 +1,325 @@
+use std::{
+    ffi::OsString,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use tokio::{
+    fs::{File, copy, metadata, read_to_string, rename, try_exists},
+    io::AsyncWriteExt,
+    process::Command,
+};
+
+use crate::{
+    CLIENT, PORT,
+    config::{ProxyMode, load_config},
+    get_pacman_configuration::pacman_conf,
+    systemd::local_addr,
+};
+
+const MIRRORLIST: &str = "/etc/pacman.d/mirrorlist";
+const SOCKET_UNIT: &str = "cacheman.socket";
+/// Marks the line below it as ours, so that it can be updated and removed.
+const MARKER: &str = "# Added by cacheman setup, removed by cacheman teardown";
+const BACKUP_SUFFIX: &str = ".cacheman-backup";
+
+#[derive(Debug, Args)]
+pub struct SetupArgs {
+    /// Add a CacheServer entry instead of a Server one. Needs pacman 6.1 or
+    /// later, which does not give up on cache servers answering 404. Implied
+    /// by the peers-only proxy mode.
+    #[arg(long)]
+    cache_server: bool,
+    /// The mirrorlist to edit.
+    #[arg(long, default_value = MIRRORLIST)]
+    mirrorlist: PathBuf,
+    /// Edit the mirrorlist even if cacheman does not answer.
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct TeardownArgs {
+    /// The mirrorlist to edit.
+    #[arg(long, default_value = MIRRORLIST)]
+    mirrorlist: PathBuf,
+    /// Edit the mirrorlist even if cacheman does not answer.
+    #[arg(long)]
+    force: bool,
+}
+
+/// The first address listened on by the socket unit, from `systemctl show`,
+/// if the unit is active.
+fn socket_listener(show: &str) -> Option<SocketAddr> {
+    let mut active = false;
+    let mut listener = None;
+    for line in show.lines() {
+        match line.split_once('=') {
+            Some(("ActiveState", state)) => active = state == "active",
+            // Such as `[::]:1052 (Stream)`
+            Some(("Listen", listen)) if listener.is_none() => {
+                listener = listen
+                    .split_whitespace()
+                    .next()
+                    .and_then(|a| a.parse().ok());
+            }
+            _ => {}
+        }
+    }
+    listener.filter(|_| active)
+}
+
+/// Where cacheman is reached on this host: through the socket unit when it is
+/// active, or else where the daemon binds itself.
+async fn base_url() -> String {
+    let show = Command::new("systemctl")
+        .args(["show", "--property=ActiveState", "--property=Listen"])
+        .arg(SOCKET_UNIT)
+        .output()
+        .await;
+    let listener = show
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| socket_listener(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)));
+    format!("http://{}", local_addr(listener))
+}
+
+fn entry(base_url: &str, cache_server: bool) -> String {
+    let key = if cache_server {
+        "CacheServer"
+    } else {
+        "Server"
+    };
+    format!("{key} = {base_url}/proxy/$arch/$repo")
+}
+
+/// Whether a pacman server entry points at the local cacheman.
+fn is_cacheman(base_url: &str, server: &str) -> bool {
+    server.starts_with(&format!("{base_url}/"))
+}
+
+/// Returns the content without the entries added by setup.
+fn remove(content: &str) -> String {
+    if !content.lines().any(|line| line == MARKER) {
+        return content.to_string();
+    }
+    let mut lines = content.lines();
+    let mut kept = String::new();
+    while let Some(line) = lines.next() {
+        if line == MARKER {
+            lines.next();
+            continue;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    kept
+}
+
+/// Returns the content with the entry first, replacing one added before.
+fn insert(content: &str, entry: &str) -> String {
+    format!("{MARKER}\n{entry}\n{}", remove(content))
+}
+
+async fn check_daemon(base_url: &str) -> Result<()> {
+    let url = format!("{base_url}/proxy/mirrorlist");
+    let response = CLIENT
+        .get(&url)
+        .timeout(Duration::from_secs(3))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = response {
+        bail!(
+            "cacheman does not answer on {url}: {e}\n\
+            Start it with `systemctl enable --now cacheman`, or pass --force"
+        );
+    }
+    Ok(())
+}
+
+/// Rewrites the file if its content changes, keeping the one found the first
+/// time next to it. Returns whether it changed.
+async fn edit(path: &Path, change: impl FnOnce(&str) -> String) -> Result<bool> {
+    let content = read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let changed = change(&content);
+    if changed == content {
+        return Ok(false);
+    }
+    let with_suffix = |suffix: &str| {
+        let mut path = OsString::from(path);
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    // Running setup again must not replace the original with our own edit
+    let backup = with_suffix(BACKUP_SUFFIX);
+    if !try_exists(&backup).await? {
+        copy(path, &backup)
+            .await
+            .with_context(|| format!("Failed to back up {}", path.display()))?;
+        println!(
+            "Saved the previous {} as {}",
+            path.display(),
+            backup.display()
+        );
+    }
+    // pacman must never see a half-written mirrorlist
+    let temporary = with_suffix(".cacheman-new");
+    let permissions = metadata(path).await?.permissions();
+    let mut file = File::create(&temporary).await?;
+    file.set_permissions(permissions).await?;
+    file.write_all(changed.as_bytes()).await?;
+    file.sync_all().await?;
+    rename(&temporary, path)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(true)
+}
+
+/// Points pacman at cacheman through the mirrorlist.
+pub async fn setup(args: SetupArgs) -> Result<()> {
+    let base_url = base_url().await;
+    if !args.force {
+        check_daemon(&base_url).await?;
+    }
+    // Repositories listing their servers themselves do not go through it,
+    // unless one of their cache servers is cacheman already
+    let pacman_conf = pacman_conf(None).await?;
+    let mut skipped = pacman_conf
+        .repositories
+        .keys()
+        .filter(|repository| {
+            pacman_conf
+                .includes
+                .get(*repository)
+                .is_none_or(|includes| !includes.contains(&args.mirrorlist))
+        })
+        .filter(|repository| {
+            pacman_conf
+                .cache_servers
+                .get(*repository)
+                .is_none_or(|servers| !servers.iter().any(|server| is_cacheman(&base_url, server)))
+        })
+        .collect::<Vec<_>>();
+    skipped.sort();
+    for repository in skipped {
+        println!(
+            "[{repository}] does not include {}, so it does not use cacheman",
+            args.mirrorlist.display()
+        );
+    }
+    // Misses are answered with 404 in peers-only mode, which only pacman
+    // cache servers get past
+    let peers_only = load_config(None).await?.proxy.mode == ProxyMode::PeersOnly;
+    let entry = entry(&base_url, args.cache_server || peers_only);
+    if edit(&args.mirrorlist, |content| insert(content, &entry)).await? {
+        println!("Added `{entry}` to {}", args.mirrorlist.display());
+    } else {
+        println!("{} already uses cacheman", args.mirrorlist.display());
+    }
+    Ok(())
+}
+
+/// Removes what `setup` added.
+pub async fn teardown(args: TeardownArgs) -> Result<()> {
+    if !args.force {
+        check_daemon(&base_url().await).await?;
+    }
+    if edit(&args.mirrorlist, remove).await? {
+        println!("Removed cacheman from {}", args.mirrorlist.display());
+    } else {
+        println!("{} does not use cacheman", args.mirrorlist.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use anyhow::Result;
+    use indoc::indoc;
+    use tempfile::tempdir;
+    use tokio::fs::{metadata, read_to_string, set_permissions, write};
+
+    use crate::setup::{edit, entry, insert, is_cacheman, remove, socket_listener};
+
+    const MIRRORLIST: &str = indoc!(
+        "
+        ## Worldwide
+        Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+        "
+    );
+    const BASE_URL: &str = "http://127.0.0.1:1052";
+
+    #[test]
+    fn insert_is_idempotent() {
+        let added = insert(MIRRORLIST, &entry(BASE_URL, false));
+        assert_eq!(
+            added,
+            indoc!(
+                "
+                # Added by cacheman setup, removed by cacheman teardown
+                Server = http://127.0.0.1:1052/proxy/$arch/$repo
+                ## Worldwide
+                Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+                "
+            )
+        );
+        assert_eq!(insert(&added, &entry(BASE_URL, false)), added);
+        // Switching to a cache server replaces the entry
+        let switched = insert(&added, &entry(BASE_URL, true));
+        assert_eq!(switched.matches("127.0.0.1").count(), 1);
+        assert!(switched.contains("CacheServer = http://127.0.0.1:1052"));
+        assert_eq!(remove(&switched), MIRRORLIST);
+        assert_eq!(remove(MIRRORLIST), MIRRORLIST);
+    }
+    #[test]
+    fn detects_cacheman() {
+        assert!(is_cacheman(
+            BASE_URL,
+            "http://127.0.0.1:1052/proxy/x86_64/core"
+        ));
+        assert!(!is_cacheman(
+            BASE_URL,
+            "http://127.0.0.1:8080/proxy/x86_64/core"
+        ));
+        assert!(!is_cacheman(
+            BASE_URL,
+            "https://geo.mirror.pkgbuild.com/core/os/x86_64"
+        ));
+    }
+    #[test]
+    fn listener_of_active_socket() {
+        let show = "ActiveState=active\nListen=[::]:1053 (Stream)\n";
+        assert_eq!(socket_listener(show), Some("[::]:1053".parse().unwrap()));
+        let show = "ActiveState=inactive\nListen=[::]:1053 (Stream)\n";
+        assert_eq!(socket_listener(show), None);
+        assert_eq!(socket_listener("ActiveState=inactive\n"), None);
+    }
+    #[tokio::test]
+    async fn edit_keeps_backup() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mirrorlist");
+        write(&path, MIRRORLIST).await?;
+        set_permissions(&path, Permissions::from_mode(0o640)).await?;
+        let add =
+            |cache_server| move |content: &str| insert(content, &entry(BASE_URL, cache_server));
+        assert!(edit(&path, add(false)).await?);
+        assert!(!edit(&path, add(false)).await?);
+        assert_eq!(metadata(&path).await?.permissions().mode() & 0o777, 0o640);
+        // The backup keeps the mirrorlist found before the first edit
+        assert!(edit(&path, add(true)).await?);
+        let backup = dir.path().join("mirrorlist.cacheman-backup");
+        assert_eq!(read_to_string(&backup).await?, MIRRORLIST);
+        assert!(edit(&path, remove).await?);
+        assert_eq!(read_to_string(&path).await?, MIRRORLIST);
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use log::{debug, info, warn};
+use tokio::{join, time::timeout};
+use zbus::{Connection, zvariant::OwnedFd};
+use zbus_binding::ManagerProxy;
+
+use crate::{
+    activity::{Activity, PauseReason},
+    upload::Uploads,
+};
+
+mod zbus_binding;
+
+/// Longest a suspend waits for transfers and for the advertisement to be
+/// withdrawn, below the 5 s logind allows by default.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// Delays sleep until the node has left the LAN, as long as it is held.
+async fn inhibit(manager: &ManagerProxy<'_>) -> Option<OwnedFd> {
+    let inhibitor = manager
+        .inhibit(
+            "sleep",
+            "cacheman",
+            "Withdrawing from the LAN cache",
+            "delay",
+        )
+        .await;
+    match inhibitor {
+        Ok(inhibitor) => Some(inhibitor),
+        Err(e) => {
+            debug!("Failed to delay sleep: {e:#}");
+            None
+        }
+    }
+}
+
+/// Pauses the node before the machine sleeps, so that others stop using it,
+/// and resumes it on wakeup, browsing for peers from scratch and gossiping
+/// right away.
+pub async fn watch(activity: &Activity, uploads: &Uploads) {
+    let signals = async {
+        let connection = Connection::system().await?;
+        let manager = ManagerProxy::new(&connection).await?;
+        let signals = manager.receive_prepare_for_sleep().await?;
+        anyhow::Ok((manager, signals))
+    };
+    let (manager, mut signals) = match signals.await {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Failed to watch systemd-logind: {e:#}");
+            return;
+        }
+    };
+    let mut inhibitor = inhibit(&manager).await;
+    while let Some(signal) = signals.next().await {
+        let Ok(args) = signal.args() else {
+            continue;
+        };
+        if args.start {
+            activity.set(PauseReason::Sleeping, true);
+            let drained = timeout(DRAIN_TIMEOUT, uploads.drain());
+            let withdrawn = timeout(DRAIN_TIMEOUT, activity.wait_until_withdrawn());
+            let (drained, withdrawn) = join!(drained, withdrawn);
+            if drained.is_err() {
+                info!("Sleeping with transfers still running");
+            }
+            if withdrawn.is_err() {
+                warn!("Sleeping before the advertisement was withdrawn");
+            }
+            drop(inhibitor.take());
+        } else {
+            activity.set(PauseReason::Sleeping, false);
+            activity.wake();
+            inhibitor = inhibit(&manager).await;
+        }
+    }
+}
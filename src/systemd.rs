@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
+    os::fd::FromRawFd,
+    time::Duration,
+};
+
+use actix_web::{HttpResponse, get, web};
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use sd_notify::{NotifyState, listen_fds_with_names, notify, watchdog_enabled};
+use tokio::{spawn, time::interval};
+
+use crate::{
+    CLIENT,
+    activity::{Activity, Scope},
+    pacman::Pacman,
+    peer_registry::PeerRegistry,
+};
+
+/// Name of the passed sockets to serve over TLS, set with
+/// `FileDescriptorName=` in the socket unit.
+const TLS_SOCKET_NAME: &str = "tls";
+/// How often the status shown by `systemctl status` is refreshed.
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Sockets passed by systemd socket activation.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    pub http: Vec<TcpListener>,
+    pub tls: Vec<TcpListener>,
+}
+
+pub fn listeners() -> Result<Listeners> {
+    let mut listeners = Listeners::default();
+    let fds = listen_fds_with_names(true).context("Failed to get the sockets from systemd")?;
+    for (fd, name) in fds {
+        // systemd hands each socket over to this process exactly once
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        if name == TLS_SOCKET_NAME {
+            listeners.tls.push(listener);
+        } else {
+            listeners.http.push(listener);
+        }
+    }
+    Ok(listeners)
+}
+
+/// Sends the states to systemd, when it started the process with `Type=notify`.
+pub fn notify_state(states: &[NotifyState]) {
+    if let Err(e) = notify(false, states) {
+        debug!("Failed to notify systemd: {e}");
+    }
+}
+
+/// Answered by the workers, so that the watchdog goes through the HTTP server.
+#[get("/health")]
+pub async fn service_health() -> HttpResponse {
+    HttpResponse::NoContent().finish()
+}
+
+/// Where this host reaches a server listening on the address, through the
+/// loopback interface when it listens on every address.
+pub fn local_addr(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+fn health_url(addr: SocketAddr) -> String {
+    format!("http://{}/health", local_addr(addr))
+}
+
+/// Pings the watchdog at half its timeout, whenever the server answers on the
+/// address, so that a server that stopped accepting or answering is
+/// restarted.
+pub fn run_watchdog(addr: SocketAddr) {
+    let mut usec = 0;
+    if !watchdog_enabled(true, &mut usec) {
+        return;
+    }
+    let url = health_url(addr);
+    spawn(async move {
+        let period = Duration::from_micros(usec) / 2;
+        let mut interval = interval(period);
+        loop {
+            interval.tick().await;
+            let response = CLIENT.get(&url).timeout(period).send().await;
+            match response.and_then(|response| response.error_for_status()) {
+                Ok(_) => notify_state(&[NotifyState::Watchdog]),
+                Err(e) => warn!("The server does not answer on {url}: {e}"),
+            }
+        }
+    });
+}
+
+fn status(
+    peers: usize,
+    upstream_urls: &HashMap<String, Vec<String>>,
+    activity: &Activity,
+) -> String {
+    let mirrored = upstream_urls
+        .values()
+        .filter(|urls| !urls.is_empty())
+        .count();
+    let mut status = format!("{} peers", peers);
+    // Empty when upstream mirrors are not used
+    if !upstream_urls.is_empty() {
+        status.push_str(&format!(
+            ", {}/{} repositories with a working mirror",
+            mirrored,
+            upstream_urls.len()
+        ));
+    }
+    if activity.is_paused(Scope::Lan) {
+        status.push_str(", paused");
+    } else if activity.is_paused(Scope::Serving) {
+        status.push_str(", not serving");
+    }
+    status
+}
+
+/// Keeps the status line of the unit up to date.
+pub fn report_status(
+    peer_registry: web::Data<PeerRegistry>,
+    pacman: web::Data<Pacman>,
+    activity: web::Data<Activity>,
+) {
+    if env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    spawn(async move {
+        let mut interval = interval(STATUS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let upstream_urls = &pacman.current().upstream_urls;
+            let status = status(peer_registry.snapshot().len(), upstream_urls, &activity);
+            notify_state(&[NotifyState::Status(&status)]);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        activity::{Activity, PauseReason},
+        systemd::{health_url, status},
+    };
+
+    #[test]
+    fn health_through_loopback() {
+        assert_eq!(
+            health_url("0.0.0.0:1052".parse().unwrap()),
+            "http://127.0.0.1:1052/health"
+        );
+        assert_eq!(
+            health_url("[::]:1052".parse().unwrap()),
+            "http://[::1]:1052/health"
+        );
+        assert_eq!(
+            health_url("192.168.1.2:8080".parse().unwrap()),
+            "http://192.168.1.2:8080/health"
+        );
+    }
+
+    #[test]
+    fn status_line() {
+        let upstream_urls = HashMap::from([
+            ("core".to_string(), vec!["https://mirror/core".to_string()]),
+            ("extra".to_string(), Vec::new()),
+        ]);
+        let activity = Activity::default();
+        assert_eq!(
+            status(3, &upstream_urls, &activity),
+            "3 peers, 1/2 repositories with a working mirror"
+        );
+        activity.set(PauseReason::OnBattery, true);
+        assert_eq!(
+            status(0, &upstream_urls, &activity),
+            "0 peers, 1/2 repositories with a working mirror, not serving"
+        );
+        assert_eq!(
+            status(2, &HashMap::new(), &activity),
+            "2 peers, not serving"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    sync::{Arc, OnceLock},
+};
+
+use actix_web::web;
+use anyhow::{Context, Result};
+use log::info;
+use rcgen::{CertifiedKey, generate_simple_self_signed};
+use reqwest::Client;
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{CLIENT, config::TlsConfig, peer_registry::PeerRegistry};
+
+/// Client for peers reached over TLS, set up once the peer registry exists.
+static TLS_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Where a peer serves over TLS and the certificate it uses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsEndpoint {
+    pub port: u16,
+    pub fingerprint: String,
+}
+
+/// Lowercase hex SHA-256 of the DER certificate.
+pub fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Loads the certificate of this node, generating a self-signed one on first
+/// start.
+pub fn load_server_config(
+    config: &TlsConfig,
+    hostname: &str,
+) -> Result<(ServerConfig, TlsEndpoint)> {
+    if !config.cert_file.exists() {
+        generate_certificate(config, hostname)?;
+    }
+    let certificate = CertificateDer::from_pem_file(&config.cert_file)
+        .context(format!("Failed to read {}", config.cert_file.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_file)
+        .context(format!("Failed to read {}", config.key_file.display()))?;
+    let endpoint = TlsEndpoint {
+        port: config.port,
+        fingerprint: fingerprint(&certificate),
+    };
+    info!("TLS certificate fingerprint: {}", endpoint.fingerprint);
+    let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate], key)?;
+    Ok((server_config, endpoint))
+}
+
+fn generate_certificate(config: &TlsConfig, hostname: &str) -> Result<()> {
+    let CertifiedKey { cert, key_pair } =
+        generate_simple_self_signed(vec![hostname.to_string(), format!("{}.local", hostname)])?;
+    for path in [&config.cert_file, &config.key_file] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&config.key_file)
+        .and_then(|mut file| file.write_all(key_pair.serialize_pem().as_bytes()))
+        .context(format!("Failed to write {}", config.key_file.display()))?;
+    fs::write(&config.cert_file, cert.pem())
+        .context(format!("Failed to write {}", config.cert_file.display()))?;
+    info!(
+        "Generated a self-signed certificate in {}",
+        config.cert_file.display()
+    );
+    Ok(())
+}
+
+/// Accepts the certificate a peer published through discovery, or one of the
+/// trusted fingerprints, instead of going through certificate authorities.
+#[derive(Debug)]
+struct PinnedVerifier {
+    peer_registry: web::Data<PeerRegistry>,
+    trusted_fingerprints: HashSet<String>,
+    provider: Arc<CryptoProvider>,
+}
+impl PinnedVerifier {
+    fn is_pinned(&self, host: &str, fingerprint: &str) -> bool {
+        self.trusted_fingerprints.contains(fingerprint)
+            || self
+                .peer_registry
+                .tls(host)
+                .is_some_and(|endpoint| endpoint.fingerprint == fingerprint)
+    }
+}
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(address) => IpAddr::from(*address).to_string(),
+            _ => String::new(),
+        };
+        if self.is_pinned(&host, &fingerprint(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Sets up the client used for `https` peer URLs.
+pub fn init_client(config: &TlsConfig, peer_registry: web::Data<PeerRegistry>) -> Result<()> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier = PinnedVerifier {
+        peer_registry,
+        trusted_fingerprints: config
+            .trusted_fingerprints
+            .iter()
+            .map(|fingerprint| fingerprint.to_ascii_lowercase())
+            .collect(),
+        provider: provider.clone(),
+    };
+    let client_config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    let client = Client::builder()
+        .use_preconfigured_tls(client_config)
+        .build()?;
+    let _ = TLS_CLIENT.set(client);
+    Ok(())
+}
+
+/// The client for a URL of a peer: `https` ones are only reached with pinned
+/// certificates.
+pub fn peer_client(url: &str) -> &'static Client {
+    match TLS_CLIENT.get() {
+        Some(client) if url.starts_with("https://") => client,
+        _ => &CLIENT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use crate::{
+        config::TlsConfig,
+        tls::{fingerprint, load_server_config},
+    };
+
+    #[test]
+    fn certificate_is_kept() -> Result<()> {
+        let dir = tempdir()?;
+        let config = TlsConfig {
+            enabled: true,
+            cert_file: dir.path().join("tls.crt"),
+            key_file: dir.path().join("tls.key"),
+            ..Default::default()
+        };
+        let (_, first) = load_server_config(&config, "cachebox")?;
+        let (_, second) = load_server_config(&config, "cachebox")?;
+        assert_eq!(first, second);
+        assert_eq!(first.port, 1053);
+        assert_eq!(first.fingerprint.len(), 64);
+        Ok(())
+    }
+    #[test]
+    fn fingerprint_is_sha256() {
+        assert_eq!(
+            fingerprint(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
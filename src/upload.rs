@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    net::IpAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    HttpResponse,
+    body::{BodySize, BodyStream, BoxBody, MessageBody, SizedStream},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{Method, header::RETRY_AFTER},
+    middleware::Next,
+    web::{self, Bytes},
+};
+use chrono::Local;
+use futures::{
+    Stream, StreamExt,
+    stream::{poll_fn, try_unfold},
+};
+use log::warn;
+use tokio::time::sleep;
+
+use crate::{
+    activity::{Activity, Scope},
+    config::{IoPriority, UploadConfig, UploadLimits},
+};
+
+/// What a busy node asks others to wait before trying it again.
+const RETRY_AFTER_SECS: u64 = 5;
+/// Same for a node that stopped serving, which is unlikely to resume soon.
+const PAUSED_RETRY_AFTER_SECS: u64 = 60;
+/// How far a transfer may run ahead of its rate.
+const BURST: Duration = Duration::from_secs(1);
+/// How often to check whether transfers are over while draining.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+/// Per-client buckets idle for longer are full again and can be dropped.
+const BUCKET_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes that may be sent right away; negative when running behind.
+    available: f64,
+    updated: Instant,
+}
+impl TokenBucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            available: 0.0,
+            updated: now,
+        }
+    }
+    /// Takes the bytes and returns how long to wait before sending them.
+    fn take(&mut self, rate_kib: u64, bytes: usize, now: Instant) -> Duration {
+        let rate = (rate_kib.max(1) * 1024) as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * rate).min(rate * BURST.as_secs_f64());
+        self.updated = now;
+        self.available -= bytes as f64;
+        if self.available < 0.0 {
+            Duration::from_secs_f64(-self.available / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Limits what `/cache` hands out to other nodes.
+#[derive(Debug)]
+pub struct Uploads {
+    config: UploadConfig,
+    transfers: AtomicUsize,
+    global: Mutex<TokenBucket>,
+    peers: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+impl Uploads {
+    pub fn new(config: &UploadConfig) -> Self {
+        Self {
+            config: config.clone(),
+            transfers: AtomicUsize::new(0),
+            global: Mutex::new(TokenBucket::new(Instant::now())),
+            peers: Mutex::default(),
+        }
+    }
+    fn limits(&self) -> UploadLimits {
+        self.config.limits_at(Local::now().time())
+    }
+    fn is_busy(&self) -> bool {
+        self.limits()
+            .max_transfers
+            .is_some_and(|max| self.transfers.load(Ordering::Relaxed) >= max)
+    }
+    /// Counts a transfer until the guard is dropped, unless there are already
+    /// too many.
+    fn try_start(uploads: &web::Data<Self>) -> Option<Transfer> {
+        let max = uploads.limits().max_transfers.unwrap_or(usize::MAX);
+        uploads
+            .transfers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |transfers| {
+                (transfers < max).then_some(transfers + 1)
+            })
+            .ok()?;
+        Some(Transfer(uploads.clone()))
+    }
+    /// Returns once no transfer is running.
+    pub async fn drain(&self) {
+        while self.transfers.load(Ordering::Relaxed) > 0 {
+            sleep(DRAIN_INTERVAL).await;
+        }
+    }
+    fn delay(&self, peer: Option<IpAddr>, bytes: usize) -> Duration {
+        let limits = self.limits();
+        let now = Instant::now();
+        let mut delay = Duration::ZERO;
+        if let Some(rate) = limits.max_rate_kib {
+            delay = self.global.lock().unwrap().take(rate, bytes, now);
+        }
+        if let (Some(rate), Some(peer)) = (limits.max_rate_per_peer_kib, peer) {
+            let mut peers = self.peers.lock().unwrap();
+            peers.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < BUCKET_IDLE);
+            let bucket = peers.entry(peer).or_insert_with(|| TokenBucket::new(now));
+            delay = delay.max(bucket.take(rate, bytes, now));
+        }
+        delay
+    }
+}
+
+struct Transfer(web::Data<Uploads>);
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.0.transfers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn unavailable(
+    request: ServiceRequest,
+    retry_after_secs: u64,
+    reason: &'static str,
+) -> ServiceResponse<BoxBody> {
+    let response = HttpResponse::ServiceUnavailable()
+        .insert_header((RETRY_AFTER, retry_after_secs))
+        .body(reason);
+    request.into_response(response)
+}
+fn busy(request: ServiceRequest) -> ServiceResponse<BoxBody> {
+    unavailable(request, RETRY_AFTER_SECS, "Too many transfers")
+}
+
+/// Answers 503 while serving is paused or too many transfers run, and paces
+/// the others to the configured rates.
+pub async fn throttle(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let uploads = request
+        .app_data::<web::Data<Uploads>>()
+        .cloned()
+        .expect("Uploads is registered as app data");
+    let activity = request
+        .app_data::<web::Data<Activity>>()
+        .cloned()
+        .expect("Activity is registered as app data");
+    // Transfers already running are left to finish
+    if activity.is_paused(Scope::Serving) {
+        return Ok(unavailable(request, PAUSED_RETRY_AFTER_SECS, "Paused"));
+    }
+    // Answering a probe would send another download here
+    if request.method() == Method::HEAD {
+        if uploads.is_busy() {
+            return Ok(busy(request));
+        }
+        return Ok(next.call(request).await?.map_into_boxed_body());
+    }
+    let Some(transfer) = Uploads::try_start(&uploads) else {
+        return Ok(busy(request));
+    };
+    let peer = request.peer_addr().map(|addr| addr.ip());
+    let response = next.call(request).await?;
+    Ok(response.map_body(|_, body| {
+        let size = body.size();
+        let mut body = Box::pin(body);
+        let chunks = poll_fn(move |cx| body.as_mut().poll_next(cx)).then(move |chunk| {
+            let _transfer = &transfer;
+            let delay = match &chunk {
+                Ok(bytes) => uploads.delay(peer, bytes.len()),
+                Err(_) => Duration::ZERO,
+            };
+            async move {
+                sleep(delay).await;
+                chunk.map_err(Into::into)
+            }
+        });
+        match size {
+            BodySize::Sized(size) => SizedStream::new(size, chunks).boxed(),
+            _ => BodyStream::new(chunks).boxed(),
+        }
+    }))
+}
+
+/// Size of the chunks read from files handed out, as in actix-files.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Sets the I/O priority of the calling thread until dropped.
+struct IoPriorityGuard;
+impl IoPriorityGuard {
+    fn set(priority: IoPriority) -> Option<Self> {
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        if priority == IoPriority::Normal {
+            return None;
+        }
+        set_io_priority(IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT).then_some(Self)
+    }
+}
+impl Drop for IoPriorityGuard {
+    fn drop(&mut self) {
+        // No class: the priority follows the CPU niceness again
+        set_io_priority(0);
+    }
+}
+
+fn set_io_priority(ioprio: libc::c_int) -> bool {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    // Thread 0 is the calling thread
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+    // Reported once, as it fails the same way for every chunk
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if result != 0 && !WARNED.swap(true, Ordering::Relaxed) {
+        warn!(
+            "Failed to set the I/O priority: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    result == 0
+}
+
+/// Reads `length` bytes of the file from `offset` in chunks, on the blocking
+/// threads. Only these reads run at the priority, so the other work of the
+/// threads keeps its own.
+pub fn read_chunks(
+    file: File,
+    offset: u64,
+    length: u64,
+    priority: IoPriority,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    try_unfold(
+        (file, offset, offset + length),
+        move |(mut file, offset, end)| async move {
+            if offset >= end {
+                return Ok(None);
+            }
+            let max = (end - offset).min(CHUNK_SIZE);
+            let (file, chunk) = web::block(move || {
+                let _priority = IoPriorityGuard::set(priority);
+                file.seek(SeekFrom::Start(offset))?;
+                let mut chunk = Vec::with_capacity(max as usize);
+                file.by_ref().take(max).read_to_end(&mut chunk)?;
+                if chunk.is_empty() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                Ok((file, Bytes::from(chunk)))
+            })
+            .await??;
+            let next = offset + chunk.len() as u64;
+            Ok(Some((chunk, (file, next, end))))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        time::{Duration, Instant},
+    };
+
+    use actix_web::web::Data;
+    use futures::TryStreamExt;
+    use tempfile::tempfile;
+    use tokio::time::timeout;
+
+    use crate::{
+        config::{IoPriority, UploadConfig, UploadLimits},
+        upload::{TokenBucket, Uploads, read_chunks},
+    };
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(start);
+        assert_eq!(bucket.take(1, 512, start), Duration::from_millis(500));
+        // Time passing pays the debt back
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(1, 512, later), Duration::ZERO);
+        // Idle time only builds up to the burst
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.take(1, 2048, much_later), Duration::from_secs(1));
+    }
+    #[test]
+    fn transfers_are_capped() {
+        let uploads = Data::new(Uploads::new(&UploadConfig {
+            limits: UploadLimits {
+                max_transfers: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        let first = Uploads::try_start(&uploads);
+        assert!(first.is_some());
+        assert!(uploads.is_busy());
+        assert!(Uploads::try_start(&uploads).is_none());
+        drop(first);
+        assert!(!uploads.is_busy());
+        assert!(Uploads::try_start(&uploads).is_some());
+    }
+    #[tokio::test]
+    async fn drain() {
+        let uploads = Data::new(Uploads::new(&UploadConfig::default()));
+        let transfer = Uploads::try_start(&uploads);
+        let drained = timeout(Duration::from_millis(300), uploads.drain());
+        assert!(drained.await.is_err());
+        drop(transfer);
+        uploads.drain().await;
+    }
+    #[actix_web::test]
+    async fn reads_part_of_file() -> anyhow::Result<()> {
+        let mut file = tempfile()?;
+        file.write_all(&[7; 100_000])?;
+        for priority in [IoPriority::Normal, IoPriority::Idle] {
+            let chunks = read_chunks(file.try_clone()?, 10, 70_000, priority)
+                .try_collect::<Vec<_>>()
+                .await
+                .expect("the file is long enough");
+            assert_eq!(chunks.len(), 2);
+            assert_eq!(
+                chunks.iter().map(|chunk| chunk.len()).sum::<usize>(),
+                70_000
+            );
+        }
+        // A file shorter than announced ends the body with an error
+        let short = read_chunks(file, 99_990, 20, IoPriority::Normal);
+        assert!(short.try_collect::<Vec<_>>().await.is_err());
+        Ok(())
+    }
+    #[test]
+    fn unlimited() {
+        let uploads = Uploads::new(&UploadConfig::default());
+        assert_eq!(uploads.delay(None, 1 << 30), Duration::ZERO);
+        assert!(!uploads.is_busy());
+    }
+}